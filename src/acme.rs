@@ -0,0 +1,812 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::client::{Client, Connector};
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use rcgen::{
+    Certificate as GeneratedCertificate, CertificateParams, CustomExtension, DistinguishedName,
+    DnType, PKCS_ECDSA_P256_SHA256,
+};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, ClientConfig, ClientHello, PrivateKey, ResolvesServerCert};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::delay_for;
+
+use crate::args::{AcmeChallenge, CliArgs};
+use crate::server::ACME_TLS_ALPN_PROTOCOL;
+use crate::tls_utils::{certificate_expiry, load_cert, load_private_key};
+
+/// Order a new certificate once the current one expires in less than this many days.
+const RENEW_BEFORE_DAYS: i64 = 30;
+
+/// How often to check whether the certificate needs to be renewed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long to wait before trying again after a failed order.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait between polls of pending authorizations and orders.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often to poll pending authorizations and orders before giving up.
+const POLL_ATTEMPTS: usize = 60;
+
+/// Path prefix under which ACME HTTP-01 challenges are requested.
+const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Certificates and pending challenges shared between the ACME client and the listeners.
+#[derive(Default)]
+pub struct AcmeState {
+    /// The currently served certificate along with its expiry date.
+    certificate: RwLock<Option<(CertifiedKey, DateTime<Utc>)>>,
+
+    /// Pending HTTP-01 challenges (token to key authorization).
+    http_challenges: RwLock<HashMap<String, String>>,
+
+    /// Pending TLS-ALPN-01 challenges (domain to challenge certificate).
+    tls_alpn_challenges: RwLock<HashMap<String, CertifiedKey>>,
+}
+
+impl AcmeState {
+    /// Load a certificate and key from disk and start serving them.
+    fn install(&self, cert_path: &Path, key_path: &Path) -> Result<DateTime<Utc>> {
+        let certs = load_cert(cert_path)?;
+        let key = load_private_key(key_path)?;
+        let expiry = certificate_expiry(
+            certs
+                .first()
+                .ok_or_else(|| anyhow!("{} contains no certificates", cert_path.display()))?,
+        )?;
+        *self.certificate.write().unwrap() = Some((certified_key(certs, &key)?, expiry));
+        Ok(expiry)
+    }
+
+    fn expiry(&self) -> Option<DateTime<Utc>> {
+        self.certificate
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(_, expiry)| *expiry)
+    }
+}
+
+impl ResolvesServerCert for AcmeState {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let is_challenge = client_hello
+            .alpn()
+            .is_some_and(|protocols| protocols.contains(&ACME_TLS_ALPN_PROTOCOL));
+        if is_challenge {
+            let server_name: &str = client_hello.server_name()?.into();
+            return self
+                .tls_alpn_challenges
+                .read()
+                .unwrap()
+                .get(server_name)
+                .cloned();
+        }
+        self.certificate
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(certified_key, _)| certified_key.clone())
+    }
+}
+
+fn certified_key(certs: Vec<Certificate>, key: &PrivateKey) -> Result<CertifiedKey> {
+    let signing_key =
+        any_supported_type(key).map_err(|_| anyhow!("Unsupported private key type"))?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+/// Answer ACME HTTP-01 challenges and redirect everything else to HTTPS.
+pub async fn http_challenge(
+    req: HttpRequest,
+    args: web::Data<CliArgs>,
+    state: web::Data<AcmeState>,
+) -> HttpResponse {
+    if let Some(token) = req.path().strip_prefix(HTTP_CHALLENGE_PREFIX) {
+        return match state.http_challenges.read().unwrap().get(token) {
+            Some(key_authorization) => HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(key_authorization.clone()),
+            None => HttpResponse::NotFound().finish(),
+        };
+    }
+
+    let conn_info = req.connection_info();
+    let host = conn_info.host().split(':').next().unwrap_or_default();
+    let location = if args.listen.port() == 443 {
+        format!("https://{}{}", host, req.uri())
+    } else {
+        format!("https://{}:{}{}", host, args.listen.port(), req.uri())
+    };
+    HttpResponse::PermanentRedirect()
+        .header("location", location)
+        .finish()
+}
+
+/// Keep the certificate for the configured ACME domains valid.
+///
+/// A new certificate is ordered whenever there is no cached one or it is about to expire.
+pub async fn run(args: CliArgs, state: Arc<AcmeState>) {
+    loop {
+        let next_check = match ensure_certificate(&args, &state).await {
+            Ok(()) => CHECK_INTERVAL,
+            Err(e) => {
+                error!("Couldn't obtain ACME certificate: {:#}", e);
+                RETRY_INTERVAL
+            }
+        };
+        delay_for(next_check).await;
+    }
+}
+
+async fn ensure_certificate(args: &CliArgs, state: &AcmeState) -> Result<()> {
+    let (cert_path, key_path) = cache_paths(args);
+
+    if state.expiry().is_none() && cert_path.exists() && key_path.exists() {
+        match state.install(&cert_path, &key_path) {
+            Ok(expiry) => info!(
+                "Loaded cached certificate for {} (valid until {})",
+                args.acme_domains.join(", "),
+                expiry
+            ),
+            Err(e) => warn!(
+                "Ignoring cached certificate {}: {:#}",
+                cert_path.display(),
+                e
+            ),
+        }
+    }
+
+    if let Some(expiry) = state.expiry() {
+        if expiry - Utc::now() > chrono::Duration::days(RENEW_BEFORE_DAYS) {
+            return Ok(());
+        }
+    }
+
+    info!(
+        "Ordering certificate for {} from {}",
+        args.acme_domains.join(", "),
+        args.acme_directory
+    );
+    let mut acme_client = AcmeClient::new(args).await?;
+    acme_client.register(args.acme_email.as_deref()).await?;
+    let (chain, key) = acme_client
+        .order_certificate(&args.acme_domains, args.acme_challenge, state)
+        .await?;
+
+    write_file(&cert_path, chain.as_bytes(), false)?;
+    write_file(&key_path, key.as_bytes(), true)?;
+    let expiry = state.install(&cert_path, &key_path)?;
+    info!(
+        "Obtained certificate for {} (valid until {})",
+        args.acme_domains.join(", "),
+        expiry
+    );
+    Ok(())
+}
+
+/// Where to store the certificate and key for the configured set of domains.
+fn cache_paths(args: &CliArgs) -> (PathBuf, PathBuf) {
+    let name = args.acme_domains.join("+");
+    (
+        args.acme_cache.join(format!("{}.crt", name)),
+        args.acme_cache.join(format!("{}.key", name)),
+    )
+}
+
+fn write_file(path: &Path, contents: &[u8], private: bool) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("Couldn't write {}", path.display()))
+}
+
+/// Base64url encoding without padding as required all over ACME.
+fn b64(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    #[serde(default)]
+    meta: DirectoryMeta,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct DirectoryMeta {
+    terms_of_service: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    type_: String,
+    url: String,
+    token: Option<String>,
+}
+
+struct AcmeResponse {
+    location: Option<String>,
+    body: web::Bytes,
+}
+
+impl AcmeResponse {
+    fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body).context("Unexpected response from ACME server")
+    }
+}
+
+/// A minimal ACME (RFC 8555) client.
+struct AcmeClient {
+    client: Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    nonce: Option<String>,
+    kid: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(args: &CliArgs) -> Result<Self> {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        if let Some(ca_cert) = &args.acme_ca_cert {
+            for cert in load_cert(ca_cert)? {
+                client_config
+                    .root_store
+                    .add(&cert)
+                    .map_err(|e| anyhow!("Invalid ACME CA certificate: {:?}", e))?;
+            }
+        }
+        let connector = Connector::new()
+            .rustls(Arc::new(client_config))
+            .timeout(Duration::from_secs(args.timeout))
+            .finish();
+        let client = Client::builder()
+            .connector(connector)
+            .timeout(Duration::from_secs(30))
+            .finish();
+
+        let mut response = client
+            .get(args.acme_directory.as_str())
+            .send()
+            .await
+            .map_err(|e| anyhow!("Couldn't fetch ACME directory: {}", e))?;
+        let directory =
+            serde_json::from_slice(&response.body().await?).context("Invalid ACME directory")?;
+
+        let rng = SystemRandom::new();
+        let key_path = args.acme_cache.join("account.key");
+        let key_pkcs8 = if key_path.exists() {
+            fs::read(&key_path).with_context(|| format!("Couldn't read {}", key_path.display()))?
+        } else {
+            let key_pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| anyhow!("Couldn't generate ACME account key"))?;
+            write_file(&key_path, key_pkcs8.as_ref(), true)?;
+            key_pkcs8.as_ref().to_vec()
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key_pkcs8)
+            .map_err(|_| anyhow!("Invalid ACME account key in {}", key_path.display()))?;
+
+        Ok(AcmeClient {
+            client,
+            directory,
+            key,
+            rng,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .client
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Couldn't get ACME nonce: {}", e))?;
+        response
+            .headers()
+            .get("replay-nonce")
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(|nonce| nonce.to_string())
+            .ok_or_else(|| anyhow!("ACME server didn't provide a nonce"))
+    }
+
+    /// Send a JWS signed POST request. `None` as `payload` results in a POST-as-GET.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<AcmeResponse> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = jwk(&self.key),
+            }
+            let protected = b64(protected.to_string());
+            let payload = payload
+                .as_ref()
+                .map(|payload| b64(payload.to_string()))
+                .unwrap_or_default();
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| anyhow!("Couldn't sign ACME request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": b64(signature),
+            });
+
+            let mut response = self
+                .client
+                .post(url)
+                .content_type("application/jose+json")
+                .send_body(body.to_string())
+                .await
+                .map_err(|e| anyhow!("ACME request to {} failed: {}", url, e))?;
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string())
+            };
+            self.nonce = header("replay-nonce");
+            let location = header("location");
+            let status = response.status();
+            let body = response.body().limit(1 << 20).await?;
+
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "ACME server returned {} for {}: {}",
+                status,
+                url,
+                problem["detail"].as_str().unwrap_or("no details")
+            );
+        }
+    }
+
+    /// Register a new account or look up the existing one for our key.
+    ///
+    /// This agrees to the terms of service of the ACME server, which `--acme-agree-tos` makes
+    /// sure the user did.
+    async fn register(&mut self, email: Option<&str>) -> Result<()> {
+        if let Some(terms_of_service) = &self.directory.meta.terms_of_service {
+            info!(
+                "Agreeing to the ACME terms of service at {}",
+                terms_of_service
+            );
+        }
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = self.directory.new_account.clone();
+        let response = self.post(&new_account, Some(payload)).await?;
+        self.kid = Some(
+            response
+                .location
+                .ok_or_else(|| anyhow!("ACME server didn't return an account URL"))?,
+        );
+        Ok(())
+    }
+
+    /// Order a certificate for `domains` and return the PEM encoded chain and private key.
+    async fn order_certificate(
+        &mut self,
+        domains: &[String],
+        challenge: AcmeChallenge,
+        state: &AcmeState,
+    ) -> Result<(String, String)> {
+        let identifiers = domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect::<Vec<_>>();
+        let new_order = self.directory.new_order.clone();
+        let response = self
+            .post(&new_order, Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = response
+            .location
+            .clone()
+            .ok_or_else(|| anyhow!("ACME server didn't return an order URL"))?;
+        let order: Order = response.json()?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(authorization_url, challenge, state).await?;
+        }
+
+        let mut params = CertificateParams::new(domains.to_vec());
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, domains[0].clone());
+        let cert = GeneratedCertificate::from_params(params)?;
+        let csr = cert.serialize_request_der()?;
+        self.post(&order.finalize, Some(json!({ "csr": b64(csr) })))
+            .await?;
+
+        let order: Order = serde_json::from_value(self.poll_until_valid(&order_url).await?)?;
+        let certificate_url = order
+            .certificate
+            .ok_or_else(|| anyhow!("ACME server didn't return a certificate URL"))?;
+        let chain = self.post(&certificate_url, None).await?.body;
+
+        Ok((
+            String::from_utf8(chain.to_vec())?,
+            cert.serialize_private_key_pem(),
+        ))
+    }
+
+    /// Prove control over the domain of the authorization at `url`.
+    async fn authorize(
+        &mut self,
+        url: &str,
+        challenge_type: AcmeChallenge,
+        state: &AcmeState,
+    ) -> Result<()> {
+        let authorization: Authorization = self.post(url, None).await?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.type_ == challenge_type.as_str())
+            .ok_or_else(|| anyhow!("ACME server offers no {} challenge", challenge_type))?;
+        let token = challenge
+            .token
+            .ok_or_else(|| anyhow!("ACME challenge is missing its token"))?;
+        let key_authorization = key_authorization(&self.key, &token);
+
+        match challenge_type {
+            AcmeChallenge::Http01 => {
+                state
+                    .http_challenges
+                    .write()
+                    .unwrap()
+                    .insert(token.clone(), key_authorization);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let challenge_cert = tls_alpn_challenge_cert(&domain, &key_authorization)?;
+                state
+                    .tls_alpn_challenges
+                    .write()
+                    .unwrap()
+                    .insert(domain.clone(), challenge_cert);
+            }
+        }
+
+        info!("Answering {} challenge for {}", challenge_type, domain);
+        let result = match self.post(&challenge.url, Some(json!({}))).await {
+            Ok(_) => self.poll_until_valid(url).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+
+        state.http_challenges.write().unwrap().remove(&token);
+        state.tls_alpn_challenges.write().unwrap().remove(&domain);
+        result
+    }
+
+    /// Poll the resource at `url` until its status becomes `valid`.
+    async fn poll_until_valid(&mut self, url: &str) -> Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: Value = self.post(url, None).await?.json()?;
+            match resource["status"].as_str() {
+                Some("valid") => return Ok(resource),
+                Some("invalid") => bail!("ACME server considers {} invalid: {}", url, resource),
+                _ => delay_for(POLL_INTERVAL).await,
+            }
+        }
+        bail!("Timed out waiting for {} to become valid", url)
+    }
+}
+
+/// The public part of an account `key` as a JWK (RFC 7517).
+fn jwk(key: &EcdsaKeyPair) -> Value {
+    // The public key is an uncompressed point: 0x04 || x || y
+    let public_key = key.public_key().as_ref();
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": b64(&public_key[1..33]),
+        "y": b64(&public_key[33..65]),
+    })
+}
+
+/// The key authorization for a challenge `token` (RFC 8555 section 8.1).
+fn key_authorization(key: &EcdsaKeyPair, token: &str) -> String {
+    // serde_json sorts keys which gives us the canonical form required by RFC 7638.
+    let thumbprint = digest(&SHA256, jwk(key).to_string().as_bytes());
+    format!("{}.{}", token, b64(thumbprint))
+}
+
+/// Self-signed certificate for answering a TLS-ALPN-01 challenge (RFC 8737 section 3).
+fn tls_alpn_challenge_cert(domain: &str, key_authorization: &str) -> Result<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+        digest(&SHA256, key_authorization.as_bytes()).as_ref(),
+    )];
+    let cert = GeneratedCertificate::from_params(params)?;
+    certified_key(
+        vec![Certificate(cert.serialize_der()?)],
+        &PrivateKey(cert.serialize_private_key_der()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::{Body, ResponseBody};
+    use actix_web::test::TestRequest;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use x509_parser::parse_x509_certificate;
+
+    /// The P-256 key from RFC 7517 appendix A.2.
+    fn account_key() -> EcdsaKeyPair {
+        let decode = |data| base64::decode_config(data, base64::URL_SAFE_NO_PAD).unwrap();
+        let mut public_key = vec![0x04];
+        public_key.extend(decode("f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU"));
+        public_key.extend(decode("x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"));
+        EcdsaKeyPair::from_private_key_and_public_key(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &decode("jpsQnnGQmL-YBIffH1136cspYG6-0iY7X1fCE9-E9LI"),
+            &public_key,
+        )
+        .unwrap()
+    }
+
+    fn args(args: &[&str]) -> CliArgs {
+        CliArgs::parse_from(["proxyboi"].iter().chain(args))
+    }
+
+    #[test]
+    fn test_jwk() {
+        assert_eq!(
+            jwk(&account_key()).to_string(),
+            r#"{"crv":"P-256","kty":"EC","x":"f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU","y":"x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"}"#
+        );
+    }
+
+    #[test]
+    fn test_key_authorization() {
+        assert_eq!(
+            key_authorization(&account_key(), "evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA"),
+            "evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA.oKIywvGUpTVTyxMQ3bwIIeQUudfr_CkLMjCE19ECD-U"
+        );
+    }
+
+    #[test]
+    fn test_tls_alpn_challenge_cert() {
+        let key_authorization =
+            "evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA.oKIywvGUpTVTyxMQ3bwIIeQUudfr_CkLMjCE19ECD-U";
+        let certified_key = tls_alpn_challenge_cert("example.com", key_authorization).unwrap();
+        let (_, cert) = parse_x509_certificate(&certified_key.cert[0].0).unwrap();
+
+        let extension = cert
+            .extensions()
+            .iter()
+            .find(|extension| extension.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .expect("acmeIdentifier extension is missing");
+        assert!(extension.critical);
+        // An OCTET STRING holding the SHA-256 digest of the key authorization.
+        let mut expected = vec![0x04, 0x20];
+        expected.extend(digest(&SHA256, key_authorization.as_bytes()).as_ref());
+        assert_eq!(extension.value, expected.as_slice());
+
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            format!("{:?}", san.value.general_names),
+            r#"[DNSName("example.com")]"#
+        );
+    }
+
+    #[test]
+    fn test_agree_tos() {
+        let without_agreement = [
+            "proxyboi",
+            "--acme-domain",
+            "example.com",
+            "http://localhost:3000",
+        ];
+        assert!(CliArgs::try_parse_from(without_agreement).is_err());
+        let args = args(&[
+            "--acme-domain",
+            "example.com",
+            "--acme-agree-tos",
+            "http://localhost:3000",
+        ]);
+        assert!(args.acme_agree_tos);
+    }
+
+    #[test]
+    fn test_directory() {
+        // From RFC 8555 section 7.1.1
+        let directory = serde_json::from_str::<Directory>(
+            r#"{
+                "newNonce": "https://example.com/acme/new-nonce",
+                "newAccount": "https://example.com/acme/new-account",
+                "newOrder": "https://example.com/acme/new-order",
+                "newAuthz": "https://example.com/acme/new-authz",
+                "revokeCert": "https://example.com/acme/revoke-cert",
+                "keyChange": "https://example.com/acme/key-change",
+                "meta": {
+                    "termsOfService": "https://example.com/acme/terms/2017-5-30",
+                    "website": "https://www.example.com/",
+                    "caaIdentities": ["example.com"],
+                    "externalAccountRequired": false
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            directory.meta.terms_of_service.as_deref(),
+            Some("https://example.com/acme/terms/2017-5-30")
+        );
+
+        let directory = serde_json::from_str::<Directory>(
+            r#"{"newNonce": "/nonce", "newAccount": "/account", "newOrder": "/order"}"#,
+        )
+        .unwrap();
+        assert_eq!(directory.meta.terms_of_service, None);
+    }
+
+    #[test]
+    fn test_cache_paths() {
+        let args = args(&[
+            "--acme-domain",
+            "example.com",
+            "--acme-domain",
+            "www.example.com",
+            "--acme-agree-tos",
+            "--acme-cache",
+            "/var/cache/proxyboi",
+            "http://localhost:3000",
+        ]);
+        assert_eq!(
+            cache_paths(&args),
+            (
+                PathBuf::from("/var/cache/proxyboi/example.com+www.example.com.crt"),
+                PathBuf::from("/var/cache/proxyboi/example.com+www.example.com.key")
+            )
+        );
+    }
+
+    async fn challenge_response(
+        listen: &str,
+        uri: &str,
+        state: AcmeState,
+    ) -> (u16, Option<String>, Vec<u8>) {
+        let args = args(&[
+            "-l",
+            listen,
+            "--acme-domain",
+            "example.com",
+            "--acme-agree-tos",
+            "http://localhost:3000",
+        ]);
+        let req = TestRequest::with_uri(uri)
+            .header("host", "example.com")
+            .to_http_request();
+        let mut resp = http_challenge(req, web::Data::new(args), web::Data::new(state)).await;
+        let location = resp
+            .headers()
+            .get("location")
+            .map(|location| location.to_str().unwrap().to_string());
+        let body = match resp.take_body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => bytes.to_vec(),
+            _ => vec![],
+        };
+        (resp.status().as_u16(), location, body)
+    }
+
+    #[actix_rt::test]
+    async fn test_http_challenge() {
+        let state = AcmeState::default();
+        state
+            .http_challenges
+            .write()
+            .unwrap()
+            .insert("token".to_string(), "token.thumbprint".to_string());
+        assert_eq!(
+            challenge_response("0.0.0.0:443", "/.well-known/acme-challenge/token", state).await,
+            (200, None, b"token.thumbprint".to_vec())
+        );
+        assert_eq!(
+            challenge_response(
+                "0.0.0.0:443",
+                "/.well-known/acme-challenge/other",
+                AcmeState::default()
+            )
+            .await,
+            (404, None, vec![])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_http_redirect() {
+        assert_eq!(
+            challenge_response("0.0.0.0:443", "/foo?bar=1", AcmeState::default()).await,
+            (
+                308,
+                Some("https://example.com/foo?bar=1".to_string()),
+                vec![]
+            )
+        );
+        assert_eq!(
+            challenge_response("0.0.0.0:8443", "/", AcmeState::default()).await,
+            (308, Some("https://example.com:8443/".to_string()), vec![])
+        );
+    }
+}
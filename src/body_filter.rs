@@ -0,0 +1,310 @@
+use std::io::{self, Read, Write};
+
+use actix_web::http::{header, HeaderMap};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use log::warn;
+use regex::bytes::{NoExpand, Regex};
+
+/// A substitution applied to text response bodies.
+///
+/// Given to `--body-replace` as "STRING REPLACEMENT" or to `--body-replace-regex` as
+/// "REGEX REPLACEMENT" where the replacement may refer to capture groups as `$1` or `${name}`.
+/// Everything after the first whitespace is the replacement, so it may contain spaces.
+#[derive(Debug, Clone)]
+pub struct BodyFilter {
+    pattern: Regex,
+    replacement: String,
+    /// Whether `$1` and friends in the replacement refer to capture groups
+    expand: bool,
+}
+
+impl BodyFilter {
+    /// Parse a filter replacing a literal string.
+    pub fn parse_literal(s: &str) -> Result<Self, String> {
+        let (string, replacement) = split_filter(s)?;
+        Ok(BodyFilter {
+            pattern: Regex::new(&regex::escape(string)).map_err(|e| e.to_string())?,
+            replacement: replacement.to_string(),
+            expand: false,
+        })
+    }
+
+    /// Parse a filter replacing matches of a regex.
+    pub fn parse_regex(s: &str) -> Result<Self, String> {
+        let (pattern, replacement) = split_filter(s)?;
+        Ok(BodyFilter {
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement.to_string(),
+            expand: true,
+        })
+    }
+
+    fn apply(&self, body: &[u8]) -> Option<Vec<u8>> {
+        if !self.pattern.is_match(body) {
+            return None;
+        }
+        let replaced = if self.expand {
+            self.pattern.replace_all(body, self.replacement.as_bytes())
+        } else {
+            self.pattern
+                .replace_all(body, NoExpand(self.replacement.as_bytes()))
+        };
+        Some(replaced.into_owned())
+    }
+}
+
+fn split_filter(s: &str) -> Result<(&str, &str), String> {
+    s.trim_start()
+        .split_once(char::is_whitespace)
+        .filter(|(pattern, _)| !pattern.is_empty())
+        .ok_or_else(|| "Wrong body filter format (expected \"PATTERN REPLACEMENT\")".to_string())
+}
+
+/// Largest decoded body we filter, as compressed bodies may expand to many times their size.
+const MAX_DECODED_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Content codings we can undo and redo to filter compressed bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// The encoding of a body as given in its `Content-Encoding`, `None` if we don't support it.
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let encoding = match headers.get(header::CONTENT_ENCODING) {
+            Some(encoding) => encoding.to_str().ok()?.trim().to_lowercase(),
+            None => return Some(Encoding::Identity),
+        };
+        match encoding.as_str() {
+            "" | "identity" => Some(Encoding::Identity),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Decode `body`, failing if it decodes to more than `MAX_DECODED_BODY_SIZE` bytes.
+    fn decode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        let limit = MAX_DECODED_BODY_SIZE as u64 + 1;
+        match self {
+            Encoding::Identity => decoded.extend_from_slice(body),
+            Encoding::Gzip => {
+                GzDecoder::new(body).take(limit).read_to_end(&mut decoded)?;
+            }
+            Encoding::Deflate => {
+                ZlibDecoder::new(body)
+                    .take(limit)
+                    .read_to_end(&mut decoded)?;
+            }
+            Encoding::Brotli => {
+                brotli::Decompressor::new(body, 4096)
+                    .take(limit)
+                    .read_to_end(&mut decoded)?;
+            }
+        }
+        if decoded.len() > MAX_DECODED_BODY_SIZE {
+            return Err(io::Error::other(format!(
+                "decoded body is larger than {} bytes",
+                MAX_DECODED_BODY_SIZE
+            )));
+        }
+        Ok(decoded)
+    }
+
+    fn encode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Identity => Ok(body.to_vec()),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                    encoder.write_all(body)?;
+                }
+                Ok(encoded)
+            }
+        }
+    }
+}
+
+/// Whether a body of `content_type` is text we may filter.
+fn is_text(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/javascript"
+                | "application/ecmascript"
+                | "application/json"
+                | "application/xml"
+        )
+}
+
+/// Apply `filters` to a response body with `headers`, decoding and re-encoding it as needed.
+///
+/// Gives `None` if no filter matched or the body isn't text in an encoding we support, in which
+/// case it should be passed on as is.
+pub fn filter_body<'a>(
+    filters: impl IntoIterator<Item = &'a BodyFilter>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Option<Vec<u8>> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    if !is_text(content_type) || body.is_empty() {
+        return None;
+    }
+    let encoding = Encoding::from_headers(headers)?;
+    let mut filters = filters.into_iter().peekable();
+    filters.peek()?;
+
+    let decoded = match encoding.decode(body) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            warn!("Not filtering {:?} response body: {}", encoding, e);
+            return None;
+        }
+    };
+    let mut filtered = None;
+    for filter in filters {
+        if let Some(replaced) = filter.apply(filtered.as_deref().unwrap_or(&decoded)) {
+            filtered = Some(replaced);
+        }
+    }
+    encoding.encode(&filtered?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+    use pretty_assertions::assert_eq;
+
+    fn headers(content_type: &str, encoding: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(content_type).unwrap(),
+        );
+        if let Some(encoding) = encoding {
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_str(encoding).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(BodyFilter::parse_literal("http://localhost:3000 https://example.com").is_ok());
+        assert!(BodyFilter::parse_literal("missing-replacement").is_err());
+        assert!(BodyFilter::parse_regex("(unclosed x").is_err());
+    }
+
+    #[test]
+    fn test_literal() {
+        let filters =
+            [BodyFilter::parse_literal("http://localhost:3000 https://example.com").unwrap()];
+        let body = filter_body(
+            &filters,
+            &headers("text/html; charset=utf-8", None),
+            b"<a href=\"http://localhost:3000/a\">$1 http://localhost:3000</a>",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "<a href=\"https://example.com/a\">$1 https://example.com</a>"
+        );
+    }
+
+    #[test]
+    fn test_regex() {
+        let filters = [
+            BodyFilter::parse_regex(r#"http://[^/"]+/ /"#).unwrap(),
+            BodyFilter::parse_regex(r"v(\d+) version-$1").unwrap(),
+        ];
+        let body = filter_body(
+            &filters,
+            &headers("application/json", None),
+            br#"{"api": "http://backend:8080/v2"}"#,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), r#"{"api": "/version-2"}"#);
+    }
+
+    #[test]
+    fn test_untouched() {
+        let filters = [BodyFilter::parse_literal("foo bar").unwrap()];
+        assert_eq!(
+            filter_body(&filters, &headers("text/css", None), b"baz"),
+            None
+        );
+        assert_eq!(
+            filter_body(&filters, &headers("image/png", None), b"foo"),
+            None
+        );
+        assert_eq!(
+            filter_body(&filters, &headers("text/css", Some("zstd")), b"foo"),
+            None
+        );
+        assert_eq!(filter_body(&filters, &HeaderMap::new(), b"foo"), None);
+    }
+
+    #[test]
+    fn test_compressed() {
+        let filters = [BodyFilter::parse_literal("foo bar").unwrap()];
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let name = match encoding {
+                Encoding::Gzip => "gzip",
+                Encoding::Deflate => "deflate",
+                _ => "br",
+            };
+            let body = encoding.encode(b"var foo = 1;").unwrap();
+            let filtered = filter_body(
+                &filters,
+                &headers("application/javascript", Some(name)),
+                &body,
+            )
+            .unwrap();
+            assert_eq!(encoding.decode(&filtered).unwrap(), b"var bar = 1;");
+        }
+    }
+
+    #[test]
+    fn test_decoded_size_limit() {
+        let filters = [BodyFilter::parse_literal("foo bar").unwrap()];
+        let headers = headers("text/plain", Some("gzip"));
+        let mut body = b"foo".repeat(MAX_DECODED_BODY_SIZE / 3);
+        let encoded = Encoding::Gzip.encode(&body).unwrap();
+        assert_eq!(Encoding::Gzip.decode(&encoded).unwrap().len(), body.len());
+
+        body.extend_from_slice(b"foo");
+        let encoded = Encoding::Gzip.encode(&body).unwrap();
+        assert!(Encoding::Gzip.decode(&encoded).is_err());
+        assert_eq!(filter_body(&filters, &headers, &encoded), None);
+    }
+}
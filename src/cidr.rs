@@ -0,0 +1,155 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IP addresses like `10.0.0.0/8` or `2001:db8::/32`.
+///
+/// A plain address is taken as a block containing just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is part of this block.
+    ///
+    /// IPv4-mapped IPv6 addresses are treated like the IPv4 addresses they map to.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V4(ip)) => prefix_matches(
+                &network.octets(),
+                &ip.to_ipv6_mapped().octets(),
+                self.prefix_len,
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` bits of `a` and `b` are equal.
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = prefix_len as usize / 8;
+    let remaining_bits = prefix_len % 8;
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xff << (8 - remaining_bits);
+    a[full_bytes] & mask == b[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid IP address in {}", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length in {}", s))?,
+            None => max_len,
+        };
+        // Only blocks within `::ffff:0:0/96` are blocks of IPv4 addresses.
+        match addr {
+            IpAddr::V6(v6) if prefix_len >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => Ok(Cidr {
+                    addr: IpAddr::V4(v4),
+                    prefix_len: prefix_len - 96,
+                }),
+                None => Ok(Cidr { addr, prefix_len }),
+            },
+            _ => Ok(Cidr { addr, prefix_len }),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn contains(cidr: &str, ip: &str) -> bool {
+        cidr.parse::<Cidr>().unwrap().contains(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_ipv4() {
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.0.0/23", "192.168.1.255"));
+        assert!(!contains("192.168.0.0/23", "192.168.2.0"));
+        assert!(contains("0.0.0.0/0", "203.0.113.1"));
+    }
+
+    #[test]
+    fn test_ipv6() {
+        assert!(contains("2001:db8::/32", "2001:db8:1::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(!contains("2001:db8::/32", "10.0.0.1"));
+    }
+
+    #[test]
+    fn test_single_address() {
+        assert!(contains("127.0.0.1", "127.0.0.1"));
+        assert!(!contains("127.0.0.1", "127.0.0.2"));
+        assert!(contains("::1", "::1"));
+    }
+
+    #[test]
+    fn test_ipv4_mapped() {
+        assert!(contains("127.0.0.0/8", "::ffff:127.0.0.1"));
+        assert!(contains("::ffff:10.0.0.0/104", "10.0.0.1"));
+        assert!(!contains("::ffff:10.0.0.0/104", "11.0.0.1"));
+        assert_eq!(
+            "::ffff:10.0.0.0/104".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "::ffff:0.0.0.0/96".parse::<Cidr>().unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+        assert_eq!(
+            "::ffff:127.0.0.1".parse::<Cidr>().unwrap().to_string(),
+            "127.0.0.1/32"
+        );
+
+        // Shorter prefixes are IPv6 blocks, which contain far more than IPv4 addresses.
+        assert_eq!(
+            "::ffff:10.0.0.0/8".parse::<Cidr>().unwrap().to_string(),
+            "::ffff:10.0.0.0/8"
+        );
+        assert!(contains("::ffff:10.0.0.0/8", "::1"));
+        assert!(contains("::/0", "192.0.2.1"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+    }
+}
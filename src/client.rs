@@ -0,0 +1,182 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_connect::{Connect, ConnectError, Connection};
+use actix_service::Service;
+use actix_web::client::{Client, ClientBuilder, Connector};
+use actix_web::http::Uri;
+use futures::future::LocalBoxFuture;
+use log::trace;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::args::{CliArgs, UpstreamHttpVersion};
+use crate::h2c::H2cConnector;
+use crate::outbound::{Outbound, OutboundConnector};
+use crate::unix::{UnixConnector, UnixUpstream};
+
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        trace!("decoding dns: {:#?}", dns_name);
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Build the rustls `ClientConfig` used for talking to TLS upstreams.
+///
+/// The advertised ALPN protocols decide which HTTP version the upstream will talk to us.
+pub fn client_config(args: &CliArgs) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    if args.insecure {
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerifier {}));
+    } else {
+        client_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+    let protocols: &[&[u8]] = match args.upstream_http_version {
+        UpstreamHttpVersion::Auto => &[b"h2", b"http/1.1"],
+        UpstreamHttpVersion::Http11 => &[b"http/1.1"],
+        UpstreamHttpVersion::Http2 => &[b"h2"],
+    };
+    client_config.set_protocols(
+        &protocols
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect::<Vec<_>>(),
+    );
+    client_config
+}
+
+/// The upstream client shared by all requests on a single client connection.
+///
+/// With `--upstream-proxy-protocol`, connections to the upstream can't be shared between clients,
+/// but requests on the same client connection can still reuse them. The client is created on the
+/// first request.
+#[derive(Clone, Default)]
+pub struct ConnectionClient(Rc<RefCell<Option<Client>>>);
+
+impl ConnectionClient {
+    pub fn get_or_insert_with(&self, f: impl FnOnce() -> Client) -> Client {
+        self.0.borrow_mut().get_or_insert_with(f).clone()
+    }
+}
+
+/// Build the client used for talking to the upstream.
+///
+/// If `proxy_header` is given, it's sent at the start of every connection to the upstream. As
+/// the client keeps connections open for reuse, such a client must only be used for requests
+/// from the client the header was built for.
+pub fn upstream_client(
+    args: &CliArgs,
+    tls_config: Arc<ClientConfig>,
+    proxy_header: Option<Vec<u8>>,
+) -> Client {
+    let timeout = Duration::from_secs(args.timeout);
+    let unix = args.upstream.as_ref().and_then(UnixUpstream::from_url);
+    let h2c = args.upstream_http_version == UpstreamHttpVersion::Http2
+        && (unix.is_some()
+            || args
+                .upstream
+                .as_ref()
+                .is_some_and(|upstream| upstream.scheme() == "http"));
+    let proxy_header = proxy_header.map(Rc::from);
+
+    if h2c {
+        ClientBuilder::new()
+            .connector(H2cConnector::new(
+                timeout,
+                unix.map(|unix| unix.socket),
+                Outbound::new(args),
+                proxy_header,
+            ))
+            .finish()
+    } else if let Some(unix) = unix {
+        let connector = Connector::new()
+            .connector(ProxyHeaderConnector {
+                inner: UnixConnector::new(unix.socket),
+                header: proxy_header,
+            })
+            .timeout(timeout)
+            .finish();
+        ClientBuilder::new().connector(connector).finish()
+    } else {
+        let connector = Connector::new()
+            .connector(ProxyHeaderConnector {
+                inner: OutboundConnector::new(args),
+                header: proxy_header,
+            })
+            .rustls(tls_config)
+            .timeout(timeout)
+            .finish();
+        ClientBuilder::new().connector(connector).finish()
+    }
+}
+
+/// Client used for requests to arbitrary destinations in forward proxy mode.
+pub struct ForwardProxyClient(pub Client);
+
+/// Build the client used for absolute-form requests in forward proxy mode.
+///
+/// Unlike `upstream_client()`, this always connects to the host given in the request URL, after
+/// checking it against `--allow-destination` and `--deny-destination` once more.
+pub fn forward_proxy_client(args: &CliArgs, tls_config: Arc<ClientConfig>) -> ForwardProxyClient {
+    let connector = Connector::new()
+        .connector(OutboundConnector::checked(args))
+        .rustls(tls_config)
+        .timeout(Duration::from_secs(args.timeout))
+        .finish();
+    ForwardProxyClient(ClientBuilder::new().connector(connector).finish())
+}
+
+/// Connector sending a PROXY protocol header (if any) on every connection made by `inner`.
+///
+/// This happens before the TLS handshake, if there is one.
+#[derive(Clone)]
+struct ProxyHeaderConnector<T> {
+    inner: T,
+    header: Option<Rc<[u8]>>,
+}
+
+impl<T, U> Service for ProxyHeaderConnector<T>
+where
+    T: Service<Request = Connect<Uri>, Response = Connection<Uri, U>, Error = ConnectError>,
+    T::Future: 'static,
+    U: AsyncWrite + Unpin + 'static,
+{
+    type Request = Connect<Uri>;
+    type Response = Connection<Uri, U>;
+    type Error = ConnectError;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Connect<Uri>) -> Self::Future {
+        let connect = self.inner.call(req);
+        let header = self.header.clone();
+        Box::pin(async move {
+            let mut connection = connect.await?;
+            if let Some(header) = header {
+                connection.get_mut().write_all(&header).await?;
+            }
+            Ok(connection)
+        })
+    }
+}
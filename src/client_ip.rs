@@ -0,0 +1,244 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use actix_web::http::{header, HeaderMap, Uri};
+
+use crate::cidr::Cidr;
+use crate::forwarded_header::{header_values, ForwardedHeader};
+
+/// Whether `peer` is one of the proxies given to `--trusted-proxy`, so that the forwarding
+/// headers it sends can be believed.
+pub fn is_trusted(trusted_proxies: &[Cidr], peer: Option<IpAddr>) -> bool {
+    peer.is_some_and(|peer| trusted_proxies.iter().any(|cidr| cidr.contains(&peer)))
+}
+
+/// The IP address of the client a request with `headers` originates from, which reached us from
+/// `peer`.
+///
+/// Requests from trusted proxies are followed back through the proxies listed in `Forwarded`
+/// or else `X-Forwarded-For` up to the first one we don't trust, which is the client as far as we
+/// can tell. Proxies which don't add either of them may send `X-Real-IP` instead. Headers sent by
+/// anyone else can't be believed, so the client is the peer itself then.
+///
+/// Gives `None` if the client is unknown, like when a proxy hid its address.
+pub fn client_ip(
+    trusted_proxies: &[Cidr],
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Option<IpAddr> {
+    if !is_trusted(trusted_proxies, peer) {
+        return peer;
+    }
+
+    let forwarded = ForwardedHeader::parse(header_values(headers, "forwarded"));
+    let mut chain = forwarded
+        .elements
+        .iter()
+        .filter_map(|element| element.get("for"))
+        .collect::<Vec<_>>();
+    if chain.is_empty() {
+        chain = header_values(headers, "x-forwarded-for")
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|node| !node.is_empty())
+            .collect();
+    }
+    if chain.is_empty() {
+        return header_values(headers, "x-real-ip")
+            .first()
+            .and_then(|node| parse_node(node))
+            .or(peer);
+    }
+
+    let mut client = peer;
+    for node in chain.iter().rev() {
+        if !is_trusted(trusted_proxies, client) {
+            break;
+        }
+        client = parse_node(node);
+    }
+    client
+}
+
+/// The host a request for `uri` with `headers` was sent to, as given in the request target or
+/// else the `Host` header.
+///
+/// Requests from a trusted proxy (`peer_trusted`) may name the host the client originally asked
+/// for in `Forwarded` or `X-Forwarded-Host` instead. Anyone else could make up any host there.
+pub fn request_host(peer_trusted: bool, uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    if peer_trusted {
+        let forwarded = ForwardedHeader::parse(header_values(headers, "forwarded"));
+        let forwarded_host = forwarded
+            .elements
+            .iter()
+            .find_map(|element| element.get("host"))
+            .map(String::from)
+            .or_else(|| {
+                let value = *header_values(headers, "x-forwarded-host").first()?;
+                value.split(',').next().map(|host| host.trim().to_string())
+            });
+        if let Some(host) = forwarded_host.filter(|host| !host.is_empty()) {
+            return Some(host);
+        }
+    }
+    uri.authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| headers.get(header::HOST)?.to_str().ok())
+        .map(String::from)
+}
+
+/// The IP address in a node like `192.0.2.43`, `192.0.2.43:4711` or `[2001:db8::1]:4711`.
+///
+/// Obfuscated identifiers like `_hidden` and `unknown` give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, _) = rest.split_once(']')?;
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    node.parse::<IpAddr>().ok().or_else(|| {
+        let (ip, port) = node.rsplit_once(':')?;
+        port.parse::<u16>().ok()?;
+        ip.parse::<IpAddr>().ok().filter(IpAddr::is_ipv4)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{HeaderName, HeaderValue};
+    use pretty_assertions::assert_eq;
+
+    fn trusted() -> Vec<Cidr> {
+        vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]
+    }
+
+    fn resolve(peer: &str, headers: &[(&'static str, &'static str)]) -> Option<IpAddr> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        client_ip(&trusted(), Some(peer.parse().unwrap()), &map)
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.43"), ip("192.0.2.43"));
+        assert_eq!(parse_node(" 192.0.2.43:4711"), ip("192.0.2.43"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1]:4711"), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("2001:db8::1:4711x"), None);
+    }
+
+    #[test]
+    fn test_request_host() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("example.com"));
+        headers.insert(
+            HeaderName::from_static("x-forwarded-host"),
+            HeaderValue::from_static("evil.example"),
+        );
+        let origin_form = Uri::from_static("/path");
+        assert_eq!(
+            request_host(false, &origin_form, &headers).unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            request_host(true, &origin_form, &headers).unwrap(),
+            "evil.example"
+        );
+
+        headers.insert(
+            HeaderName::from_static("forwarded"),
+            HeaderValue::from_static("for=192.0.2.1;host=\"app.example:8443\""),
+        );
+        assert_eq!(
+            request_host(true, &origin_form, &headers).unwrap(),
+            "app.example:8443"
+        );
+        assert_eq!(
+            request_host(false, &origin_form, &headers).unwrap(),
+            "example.com"
+        );
+
+        // The authority of HTTP/2 requests and absolute-form targets comes before `Host`.
+        let absolute_form = Uri::from_static("https://example.org:8443/path");
+        assert_eq!(
+            request_host(false, &absolute_form, &headers).unwrap(),
+            "example.org:8443"
+        );
+        assert_eq!(request_host(false, &origin_form, &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let headers = [
+            ("forwarded", "for=192.0.2.1"),
+            ("x-forwarded-for", "192.0.2.2"),
+            ("x-real-ip", "192.0.2.3"),
+        ];
+        assert_eq!(resolve("198.51.100.1", &headers), ip("198.51.100.1"));
+        assert_eq!(client_ip(&trusted(), None, &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_forwarded() {
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[
+                    ("forwarded", "for=192.0.2.1"),
+                    ("forwarded", "For=\"[2001:db8::1]:4711\", for=10.0.0.2"),
+                    ("x-forwarded-for", "192.0.2.2"),
+                ]
+            ),
+            ip("2001:db8::1")
+        );
+        // Only what trusted proxies say counts.
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[("forwarded", "for=192.0.2.1, for=198.51.100.1")]
+            ),
+            ip("198.51.100.1")
+        );
+        assert_eq!(resolve("10.0.0.1", &[("forwarded", "for=_hidden")]), None);
+        assert_eq!(
+            resolve("::1", &[("forwarded", "for=10.0.0.3;proto=https")]),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[
+                    ("x-forwarded-for", "192.0.2.1, 198.51.100.1"),
+                    ("x-forwarded-for", "10.0.0.2"),
+                ]
+            ),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn test_x_real_ip() {
+        assert_eq!(
+            resolve("10.0.0.1", &[("x-real-ip", "192.0.2.1")]),
+            ip("192.0.2.1")
+        );
+        assert_eq!(resolve("10.0.0.1", &[]), ip("10.0.0.1"));
+    }
+}
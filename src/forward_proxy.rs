@@ -0,0 +1,389 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use actix_codec::{AsyncRead, AsyncWrite, Framed};
+use actix_http::body::BodySize;
+use actix_http::h1::{Codec, Message};
+use actix_http::http::ConnectionType;
+use actix_http::http::{Method, StatusCode, Uri};
+use actix_http::{Request, Response};
+use futures::SinkExt;
+use log::{error, info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+use url::Url;
+
+use crate::args::CliArgs;
+use crate::cidr::Cidr;
+use crate::logging::{log_tcp_connection_closed, log_tcp_connection_opened};
+use crate::outbound::Outbound;
+use crate::tcp::splice;
+
+/// A destination given to `--allow-destination` or `--deny-destination`.
+#[derive(Debug, Clone)]
+pub enum DestinationRule {
+    /// A host name (`*.example.com` matches all subdomains) with an optional port
+    Host { pattern: String, port: Option<u16> },
+
+    /// A block of IP addresses, matching destinations given as or resolving to such an address
+    Network(Cidr),
+}
+
+impl DestinationRule {
+    fn matches(&self, host: &str, port: u16, addrs: &[IpAddr]) -> bool {
+        match self {
+            DestinationRule::Host {
+                pattern,
+                port: rule_port,
+            } => {
+                let host_matches = match pattern.strip_prefix("*.") {
+                    Some(suffix) => host
+                        .to_ascii_lowercase()
+                        .strip_suffix(suffix)
+                        .is_some_and(|subdomain| subdomain.ends_with('.')),
+                    None => pattern.eq_ignore_ascii_case(host),
+                };
+                host_matches && rule_port.is_none_or(|rule_port| rule_port == port)
+            }
+            DestinationRule::Network(cidr) => addrs.iter().any(|addr| cidr.contains(addr)),
+        }
+    }
+}
+
+impl FromStr for DestinationRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let unbracketed = s.trim_start_matches('[').trim_end_matches(']');
+        if s.contains('/') || unbracketed.parse::<IpAddr>().is_ok() {
+            return Ok(DestinationRule::Network(unbracketed.parse()?));
+        }
+        let (pattern, port) = match s.rsplit_once(':') {
+            Some((pattern, port)) => (
+                pattern,
+                Some(
+                    port.parse::<u16>()
+                        .map_err(|_| format!("Invalid port in destination {}", s))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+        if name.is_empty() || name.contains('*') {
+            return Err(format!(
+                "Invalid destination {} (expected host[:port], *.domain[:port] or a CIDR block)",
+                s
+            ));
+        }
+        Ok(DestinationRule::Host {
+            pattern: pattern.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+/// Decide whether we may proxy to `host:port` according to `--allow-destination` and
+/// `--deny-destination`.
+///
+/// Denied destinations always lose, otherwise the destination has to be allowed explicitly if
+/// there are any allowed destinations. Rules for IP addresses also match host names resolving to
+/// a matching address.
+///
+/// Gives the addresses which were checked, and which therefore are the ones to connect to, so
+/// that the host name can't resolve to a different address in the meantime. They are empty if
+/// the host name didn't have to be resolved, because there are no rules for IP addresses.
+pub async fn check_destination(
+    args: &CliArgs,
+    host: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let destination = format!("{}:{}", host, port);
+    let rules = || {
+        args.allow_destinations
+            .iter()
+            .chain(&args.deny_destinations)
+    };
+    let addrs = match host.parse::<IpAddr>() {
+        Ok(addr) => vec![SocketAddr::new(addr, port)],
+        Err(_) if rules().any(|rule| matches!(rule, DestinationRule::Network(_))) => {
+            let addrs = tokio::net::lookup_host((host, port))
+                .await
+                .map(|addrs| addrs.collect::<Vec<_>>())
+                .unwrap_or_default();
+            if addrs.is_empty() {
+                return Err(format!("Couldn't resolve destination {}", destination));
+            }
+            addrs
+        }
+        Err(_) => vec![],
+    };
+    let ips = addrs.iter().map(SocketAddr::ip).collect::<Vec<_>>();
+
+    if args
+        .deny_destinations
+        .iter()
+        .any(|rule| rule.matches(host, port, &ips))
+    {
+        return Err(format!("Destination {} is denied", destination));
+    }
+    if !args.allow_destinations.is_empty()
+        && !args
+            .allow_destinations
+            .iter()
+            .any(|rule| rule.matches(host, port, &ips))
+    {
+        return Err(format!("Destination {} is not allowed", destination));
+    }
+    Ok(addrs)
+}
+
+/// The URL to request for an absolute-form request to `uri`, if we may proxy it.
+///
+/// Otherwise the status to respond with is returned along with the reason.
+pub async fn destination_url(args: &CliArgs, uri: &Uri) -> Result<Url, (StatusCode, String)> {
+    let url = Url::parse(&uri.to_string())
+        .ok()
+        .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid request URI {}", uri),
+            )
+        })?;
+    let (host, port) = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => (host, port),
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid request URI {}", uri),
+            ))
+        }
+    };
+    check_destination(args, host, port)
+        .await
+        .map_err(|reason| (StatusCode::FORBIDDEN, reason))?;
+    Ok(url)
+}
+
+/// Send a response without a body and close the connection afterwards.
+async fn respond<T>(framed: &mut Framed<T, Codec>, status: StatusCode) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut response = Response::new(status).drop_body();
+    response
+        .head_mut()
+        .set_connection_type(ConnectionType::Close);
+    framed
+        .send(Message::Item((response, BodySize::Empty)))
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Forward proxy handling CONNECT requests.
+///
+/// Absolute-form requests are regular HTTP requests and handled by `handler::forward()`.
+#[derive(Clone)]
+pub struct ForwardProxy {
+    args: CliArgs,
+    outbound: Outbound,
+}
+
+impl ForwardProxy {
+    pub fn new(args: CliArgs) -> Self {
+        ForwardProxy {
+            outbound: Outbound::new(&args),
+            args,
+        }
+    }
+
+    /// Handle a request the HTTP service handed over together with the whole connection.
+    ///
+    /// These are CONNECT requests, for which a tunnel to the requested destination is opened,
+    /// and other protocol upgrades which we don't support in forward proxy mode.
+    pub async fn connect<T>(self, request: Request, mut framed: Framed<T, Codec>) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let args = &self.args;
+        let remote = request
+            .head()
+            .peer_addr
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        if request.method() != Method::CONNECT {
+            warn!(
+                "Protocol upgrade of {} {} from {} is not supported in forward proxy mode",
+                request.method(),
+                request.uri(),
+                remote
+            );
+            return respond(&mut framed, StatusCode::NOT_IMPLEMENTED).await;
+        }
+
+        let (host, port) = match request
+            .uri()
+            .authority()
+            .and_then(|authority| Some((authority.host().to_string(), authority.port_u16()?)))
+        {
+            Some(destination) => destination,
+            None => {
+                warn!(
+                    "Invalid CONNECT destination {} from {}",
+                    request.uri(),
+                    remote
+                );
+                return respond(&mut framed, StatusCode::BAD_REQUEST).await;
+            }
+        };
+        let addrs = match check_destination(args, &host, port).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("Refused CONNECT from {}: {}", remote, e);
+                return respond(&mut framed, StatusCode::FORBIDDEN).await;
+            }
+        };
+
+        let destination = request.uri().to_string();
+        let started = Instant::now();
+        let connect = self.outbound.connect_addrs(&host, port, &addrs);
+        let mut upstream = match timeout(Duration::from_secs(args.timeout), connect).await {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(e)) => {
+                error!("Couldn't connect to {}: {}", destination, e);
+                return respond(&mut framed, StatusCode::BAD_GATEWAY).await;
+            }
+            Err(_) => {
+                error!("Timeout while connecting to {}", destination);
+                return respond(&mut framed, StatusCode::GATEWAY_TIMEOUT).await;
+            }
+        };
+
+        let mut response = Response::new(StatusCode::OK).drop_body();
+        response.head_mut().reason = Some("Connection Established");
+        framed
+            .send(Message::Item((response, BodySize::None)))
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        info!(
+            "{}",
+            log_tcp_connection_opened(&remote, &destination, None, args.verbose)
+        );
+
+        // The client may have sent data right after the request already.
+        let parts = framed.into_parts();
+        upstream.write_all(&parts.read_buf).await?;
+        let (sent, received, error) = splice(parts.io, upstream).await;
+        info!(
+            "{}",
+            log_tcp_connection_closed(
+                &remote,
+                &destination,
+                sent + parts.read_buf.len() as u64,
+                received,
+                started.elapsed(),
+                error.as_ref(),
+                args.verbose,
+            )
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+
+    fn matches(rule: &str, host: &str, port: u16, addrs: &[&str]) -> bool {
+        let addrs = addrs
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect::<Vec<_>>();
+        rule.parse::<DestinationRule>()
+            .unwrap()
+            .matches(host, port, &addrs)
+    }
+
+    #[test]
+    fn test_host_rules() {
+        assert!(matches("example.com", "example.com", 443, &[]));
+        assert!(matches("example.com", "EXAMPLE.com", 80, &[]));
+        assert!(!matches("example.com", "www.example.com", 443, &[]));
+        assert!(matches("example.com:443", "example.com", 443, &[]));
+        assert!(!matches("example.com:443", "example.com", 80, &[]));
+    }
+
+    #[test]
+    fn test_wildcard_rules() {
+        assert!(matches("*.example.com", "www.example.com", 443, &[]));
+        assert!(matches("*.example.com", "a.b.example.com", 443, &[]));
+        assert!(!matches("*.example.com", "example.com", 443, &[]));
+        assert!(!matches("*.example.com", "badexample.com", 443, &[]));
+        assert!(matches("*.example.com:443", "www.example.com", 443, &[]));
+        assert!(!matches("*.example.com:443", "www.example.com", 22, &[]));
+    }
+
+    #[test]
+    fn test_network_rules() {
+        assert!(matches("10.0.0.0/8", "10.1.2.3", 22, &["10.1.2.3"]));
+        assert!(matches("10.0.0.0/8", "internal", 22, &["10.1.2.3"]));
+        assert!(!matches("10.0.0.0/8", "example.com", 22, &["203.0.113.1"]));
+        assert!(matches("[::1]", "::1", 22, &["::1"]));
+        assert!(matches("127.0.0.1", "localhost", 22, &["127.0.0.1"]));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!("example.com:http".parse::<DestinationRule>().is_err());
+        assert!("exa*mple.com".parse::<DestinationRule>().is_err());
+        assert!("10.0.0.0/64".parse::<DestinationRule>().is_err());
+        assert!("".parse::<DestinationRule>().is_err());
+    }
+
+    fn args(args: &[&str]) -> CliArgs {
+        CliArgs::parse_from(["proxyboi", "--forward-proxy"].iter().chain(args))
+    }
+
+    #[actix_rt::test]
+    async fn test_check_destination() {
+        let args = args(&["--deny-destination", "127.0.0.0/8"]);
+        assert_eq!(
+            check_destination(&args, "192.0.2.1", 443).await,
+            Ok(vec!["192.0.2.1:443".parse().unwrap()])
+        );
+        assert_eq!(
+            check_destination(&args, "127.0.0.1", 443).await,
+            Err("Destination 127.0.0.1:443 is denied".to_string())
+        );
+        assert_eq!(
+            check_destination(&args, "localhost", 443).await,
+            Err("Destination localhost:443 is denied".to_string())
+        );
+
+        // Without rules for IP addresses, host names are left for the connection to resolve.
+        let args = self::args(&["--allow-destination", "*.example.com"]);
+        assert_eq!(
+            check_destination(&args, "www.example.com", 443).await,
+            Ok(vec![])
+        );
+        assert_eq!(
+            check_destination(&args, "[::1]", 443).await,
+            Err("Destination ::1:443 is not allowed".to_string())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_check_unresolvable_destination() {
+        let args = args(&["--deny-destination", "10.0.0.0/8"]);
+        assert_eq!(
+            check_destination(&args, "nonexistent.invalid", 80).await,
+            Err("Couldn't resolve destination nonexistent.invalid:80".to_string())
+        );
+    }
+}
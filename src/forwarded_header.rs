@@ -1,81 +1,205 @@
+use std::borrow::Cow;
 use std::fmt;
+use std::net::Ipv6Addr;
 
-#[derive(Debug, Clone, Default)]
+use actix_web::http::HeaderMap;
+
+use crate::header_order::values_in_order;
+
+/// The values of all `name` headers in the order they were received, which matters for headers
+/// listing proxies in order like `Forwarded`.
+pub fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    values_in_order(headers, name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect()
+}
+
+/// A single element of a `Forwarded` header (RFC 7239), describing one hop.
+///
+/// Parameters are kept in order with their names as given, so that elements added by previous
+/// proxies are passed on unchanged, including any extension parameters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    params: Vec<(String, String)>,
+}
+
+impl ForwardedElement {
+    /// Parse an element like `for="[2001:db8::1]:4711";proto=https`.
+    ///
+    /// Gives `None` if the element is malformed.
+    fn parse(element: &str) -> Option<Self> {
+        let mut params = vec![];
+        for pair in split_unquoted(element, ';') {
+            let pair = pair.trim();
+            if pair.is_empty() {
+                continue;
+            }
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            if !is_token(name) {
+                return None;
+            }
+            params.push((name.to_string(), unquote(value.trim())?));
+        }
+        if params.is_empty() {
+            return None;
+        }
+        Some(ForwardedElement { params })
+    }
+
+    /// The unquoted value of the parameter `name`, which is case-insensitive.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl fmt::Display for ForwardedElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.params.iter().enumerate() {
+            if i > 0 {
+                f.write_str(";")?;
+            }
+            write!(f, "{}={}", name, quote(value))?;
+        }
+        Ok(())
+    }
+}
+
+/// The `Forwarded` header to pass on: the elements of all previous proxies followed by ours.
+///
+/// The header may look like any of these, and may also be given on multiple lines which are
+/// treated as if they were joined with commas:
+///
+/// Forwarded: for=192.0.2.60
+/// Forwarded: for=192.0.2.43, For="[2001:db8:cafe::17]:4711"
+/// Forwarded: for=_hidden;proto=https;by=203.0.113.43, for=unknown;host="example.com:8443"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ForwardedHeader {
-    pub by: String,
-    pub for_: Vec<String>,
-    pub host: String,
-    pub proto: String,
+    pub elements: Vec<ForwardedElement>,
 }
 
-/// We want to append the current host to the forwarded for list.
-/// In order to do this, we have to parse the possibly existing Forwarded and X-Forwarded-For
-/// headers and append the current host value those.
-/// This is done independently for Forwarded and X-Forwarded-For because even though it would be
-/// very odd for them to have different values, it's certainly possible and not technically
-/// invalid.
-/// The Forwarded header is a bit nasty to parse. It can look like this:
-/// Forwarded: by=<by>;for=<foo>;host=<host>;proto=<http|https>
-/// but also like this
-/// Forwarded: for=<foo>
-/// but also this
-/// Forwarded: for=<foo>, for=<bar>
-/// also finally also this
-/// Forwarded: by=<by>;for=<foo>, for=<bar>;host=<host>
 impl ForwardedHeader {
-    pub fn from_info(
+    /// Parse the values of all `Forwarded` headers of a request.
+    ///
+    /// Malformed elements are skipped rather than failing the whole header.
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let elements = values
+            .into_iter()
+            .flat_map(|value| split_unquoted(value, ','))
+            .filter_map(ForwardedElement::parse)
+            .collect();
+        ForwardedHeader { elements }
+    }
+
+    /// The header with an element for this hop appended to the `forwarded` header values.
+    pub fn from_info<'a>(
         peer: &str,
         interface: &str,
-        forwarded: &str,
+        forwarded: impl IntoIterator<Item = &'a str>,
         host: &str,
         proto: &str,
     ) -> Self {
-        // Try to find a `for=` in the value of this `Forwarded` header.
-        // If we do find one, we'll have to figure out how many values there are already.
-        // If there is no `for=` just yet we can just plop in our own value and be done with it.
-        let for_start = forwarded.find("for=");
-        let mut fors = vec![];
-        if let Some(for_start) = for_start {
-            // Try to find a `:` which ends this `for` subfield.
-            // If there is none, this field is the last one in the header.
-            let forwarded_for = if let Some(for_end) = forwarded[for_start..].find(';') {
-                &forwarded[for_start..for_start + for_end]
-            } else {
-                &forwarded[for_start..forwarded.len()]
-            };
-
-            // Now, let's extract all the `for=` from this. They're all separated by `,`s.
-            for for_ in forwarded_for.split(',') {
-                fors.push(for_.to_string().replace("for=", ""));
+        let mut header = Self::parse(forwarded);
+        header.elements.push(ForwardedElement {
+            params: vec![
+                ("by".to_string(), node(interface)),
+                ("for".to_string(), node(peer)),
+                ("host".to_string(), host.to_string()),
+                ("proto".to_string(), proto.to_string()),
+            ],
+        });
+        header
+    }
+}
+
+impl fmt::Display for ForwardedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, element) in self.elements.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
             }
+            write!(f, "{}", element)?;
+        }
+        Ok(())
+    }
+}
+
+/// The node identifier for an IP address, which has to be in brackets for IPv6 (RFC 7239
+/// section 6).
+fn node(address: &str) -> String {
+    match address.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]", address),
+        Err(_) => address.to_string(),
+    }
+}
+
+/// Whether `value` is a token as defined in RFC 9110 section 5.6.2.
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Quote `value` unless it's a token.
+fn quote(value: &str) -> Cow<'_, str> {
+    if is_token(value) {
+        return Cow::Borrowed(value);
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
         }
-        fors.push(peer.to_string());
+        quoted.push(c);
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
 
-        ForwardedHeader {
-            by: interface.to_string(),
-            for_: fors,
-            host: host.to_string(),
-            proto: proto.to_string(),
+/// Undo `quote()`, `None` for values which are neither a token nor a valid quoted string.
+fn unquote(value: &str) -> Option<String> {
+    let inner = match value.strip_prefix('"') {
+        Some(rest) => rest.strip_suffix('"')?,
+        None => return is_token(value).then(|| value.to_string()),
+    };
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next()?),
+            '"' => return None,
+            c => unquoted.push(c),
         }
     }
+    Some(unquoted)
 }
 
-impl fmt::Display for ForwardedHeader {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "by={by};{for_};host={host};proto={proto}",
-            by = self.by,
-            for_ = self
-                .for_
-                .iter()
-                .map(|x| format!("for={}", x))
-                .collect::<Vec<_>>()
-                .join(", "),
-            host = self.host,
-            proto = self.proto
-        )
+/// Split `value` at every `separator` which isn't part of a quoted string.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
     }
+    parts.push(&value[start..]);
+    parts
 }
 
 #[cfg(test)]
@@ -86,7 +210,7 @@ mod tests {
     #[test]
     fn test_unknown_peer_and_empty_for() {
         let result =
-            ForwardedHeader::from_info("unknown", "0.0.0.0", "", "unknown", "http").to_string();
+            ForwardedHeader::from_info("unknown", "0.0.0.0", None, "unknown", "http").to_string();
         let expected = "by=0.0.0.0;for=unknown;host=unknown;proto=http";
         assert_eq!(result, expected);
     }
@@ -94,9 +218,9 @@ mod tests {
     #[test]
     fn test_known_peer_and_host() {
         let result =
-            ForwardedHeader::from_info("192.168.0.100", "0.0.0.0", "", "localhost:8080", "http")
+            ForwardedHeader::from_info("192.168.0.100", "0.0.0.0", None, "localhost:8080", "http")
                 .to_string();
-        let expected = "by=0.0.0.0;for=192.168.0.100;host=localhost:8080;proto=http";
+        let expected = "by=0.0.0.0;for=192.168.0.100;host=\"localhost:8080\";proto=http";
         assert_eq!(result, expected);
     }
 
@@ -105,13 +229,13 @@ mod tests {
         let result = ForwardedHeader::from_info(
             "192.168.0.100",
             "0.0.0.0",
-            "for=192.168.0.99",
+            Some("for=192.168.0.99"),
             "localhost:8080",
             "http",
         )
         .to_string();
         let expected =
-            "by=0.0.0.0;for=192.168.0.99, for=192.168.0.100;host=localhost:8080;proto=http";
+            "for=192.168.0.99, by=0.0.0.0;for=192.168.0.100;host=\"localhost:8080\";proto=http";
         assert_eq!(result, expected);
     }
 
@@ -120,13 +244,68 @@ mod tests {
         let result = ForwardedHeader::from_info(
             "192.168.0.100",
             "0.0.0.0",
-            "for=192.168.0.97,for=192.168.0.98,for=192.168.0.99",
+            Some("for=192.168.0.97,for=192.168.0.98,for=192.168.0.99"),
             "localhost:8080",
             "http",
         )
         .to_string();
         let expected =
-            "by=0.0.0.0;for=192.168.0.97, for=192.168.0.98, for=192.168.0.99, for=192.168.0.100;host=localhost:8080;proto=http";
+            "for=192.168.0.97, for=192.168.0.98, for=192.168.0.99, by=0.0.0.0;for=192.168.0.100;host=\"localhost:8080\";proto=http";
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_ipv6() {
+        let result = ForwardedHeader::from_info("2001:db8::1", "::", None, "example.com", "https")
+            .to_string();
+        let expected = "by=\"[::]\";for=\"[2001:db8::1]\";host=example.com;proto=https";
+        assert_eq!(result, expected);
+    }
+
+    fn params(element: &ForwardedElement) -> Vec<(&str, &str)> {
+        element
+            .params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let header = ForwardedHeader::parse([
+            "For=\"[2001:db8:cafe::17]:4711\";Proto=https, for=_hidden;by=_proxy1",
+            "for=unknown;host=\"example.com:8443\";secret=\"a \\\"quoted\\\" \\\\ value; with, separators\"",
+        ]);
+        assert_eq!(header.elements.len(), 3);
+        assert_eq!(
+            params(&header.elements[0]),
+            [("For", "[2001:db8:cafe::17]:4711"), ("Proto", "https")]
+        );
+        assert_eq!(
+            params(&header.elements[1]),
+            [("for", "_hidden"), ("by", "_proxy1")]
+        );
+        assert_eq!(
+            params(&header.elements[2]),
+            [
+                ("for", "unknown"),
+                ("host", "example.com:8443"),
+                ("secret", "a \"quoted\" \\ value; with, separators")
+            ]
+        );
+
+        // Passing the elements on doesn't change them.
+        assert_eq!(
+            header.to_string(),
+            "For=\"[2001:db8:cafe::17]:4711\";Proto=https, for=_hidden;by=_proxy1, for=unknown;host=\"example.com:8443\";secret=\"a \\\"quoted\\\" \\\\ value; with, separators\""
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let header = ForwardedHeader::parse([
+            "for=192.0.2.1, for=[::1], =x, , for=192.0.2.2;;, for=\"unterminated, for=192.0.2.3",
+        ]);
+        assert_eq!(header.to_string(), "for=192.0.2.1, for=192.0.2.2");
+    }
 }
@@ -0,0 +1,528 @@
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use anyhow::{anyhow, Context, Result};
+use futures::future::poll_fn;
+use futures::lock::Mutex;
+use h2::client::SendRequest;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::header::{HeaderValue, CONNECTION, TE};
+use http::{HeaderMap, Request, Response, StatusCode, Version};
+use log::{debug, info};
+use rustls::ClientConfig;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+use crate::args::CliArgs;
+use crate::client_ip::{client_ip, is_trusted};
+use crate::handler::forwarding_headers;
+use crate::header_rules::{request_id, HeaderRules, RequestVars};
+use crate::hop_by_hop::{connection_options, is_hop_by_hop};
+use crate::logging::{log_grpc_status, log_incoming_grpc_call, log_upstream_grpc_call};
+use crate::outbound::Outbound;
+use crate::proxy_protocol::{v2_header, ProxiedAddrs};
+use crate::server::ListenerInfo;
+use crate::unix::{self, UnixUpstream};
+use crate::upstream_path::upstream_url;
+
+/// gRPC status code sent to the client if the upstream can't be reached.
+const GRPC_STATUS_UNAVAILABLE: &str = "14";
+
+/// Where a call came from.
+#[derive(Clone, Copy)]
+struct Caller<'a> {
+    /// Address of the peer, which is passed on in a PROXY protocol header if enabled.
+    peer_addr: Option<SocketAddr>,
+    /// Address of the client as far as we can tell, see `client_ip()`.
+    remote: &'a str,
+    listener: ListenerInfo,
+}
+
+/// Proxies gRPC calls arriving on HTTP/2 connections.
+///
+/// actix-web has no support for trailers which gRPC uses to transmit the call status, so we speak
+/// HTTP/2 on both sides ourselves. Bodies are streamed in both directions to support streaming
+/// calls. Every client connection gets its own upstream connection which is opened on the first
+/// call.
+#[derive(Clone)]
+pub struct GrpcProxy {
+    args: CliArgs,
+    upstream: Url,
+    outbound: Outbound,
+    tls_config: Arc<ClientConfig>,
+}
+
+impl GrpcProxy {
+    /// Create a proxy forwarding to the upstream given in `args`.
+    ///
+    /// `tls_config` is used for TLS upstreams, its ALPN protocols are replaced by "h2".
+    pub fn new(args: CliArgs, mut tls_config: ClientConfig) -> Self {
+        tls_config.set_protocols(&[b"h2".to_vec()]);
+        let upstream = args
+            .upstream
+            .clone()
+            .expect("--grpc can't be combined with --forward-proxy so there always is an upstream");
+        GrpcProxy {
+            outbound: Outbound::new(&args),
+            args,
+            upstream,
+            tls_config: Arc::new(tls_config),
+        }
+    }
+
+    /// Serve all calls arriving on the HTTP/2 connection `io`.
+    pub async fn serve<S>(&self, io: S, peer_addr: Option<SocketAddr>, listener: ListenerInfo)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let mut connection = match h2::server::handshake(io).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                debug!("HTTP/2 handshake with client failed: {}", e);
+                return;
+            }
+        };
+        let upstream = Rc::new(Mutex::new(None));
+        while let Some(stream) = connection.accept().await {
+            match stream {
+                Ok((request, respond)) => {
+                    let proxy = self.clone();
+                    let upstream = upstream.clone();
+                    actix_rt::spawn(async move {
+                        proxy
+                            .proxy_call(request, respond, upstream, peer_addr, listener)
+                            .await
+                    });
+                }
+                Err(e) => {
+                    debug!("HTTP/2 connection with client failed: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Get a connection to the upstream, reconnecting if the previous one has been closed.
+    ///
+    /// `peer_addr` is the address of the client, which is passed on in a PROXY protocol header
+    /// if enabled.
+    async fn upstream(
+        &self,
+        upstream: &Mutex<Option<SendRequest<Bytes>>>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<SendRequest<Bytes>> {
+        let mut upstream = upstream.lock().await;
+        if let Some(send_request) = upstream.clone() {
+            if let Ok(send_request) = send_request.ready().await {
+                return Ok(send_request);
+            }
+        }
+        let proxy_header = if self.args.upstream_proxy_protocol {
+            Some(v2_header(peer_addr.map(|source| ProxiedAddrs {
+                source,
+                destination: self.args.listen,
+            })))
+        } else {
+            None
+        };
+        let send_request = self.connect(proxy_header).await?;
+        *upstream = Some(send_request.clone());
+        Ok(send_request)
+    }
+
+    async fn connect(&self, proxy_header: Option<Vec<u8>>) -> Result<SendRequest<Bytes>> {
+        let connect_timeout = Duration::from_secs(self.args.timeout);
+        if let Some(unix) = UnixUpstream::from_url(&self.upstream) {
+            let mut stream = timeout(connect_timeout, unix::connect(&unix.socket))
+                .await
+                .map_err(|_| anyhow!("Timeout while connecting to {}", self.upstream))??;
+            if let Some(proxy_header) = proxy_header {
+                stream.write_all(&proxy_header).await?;
+            }
+            return handshake(stream).await;
+        }
+
+        let host = self
+            .upstream
+            .host_str()
+            .context("Upstream URL has no host")?;
+        let port = self
+            .upstream
+            .port_or_known_default()
+            .context("Upstream URL has no port")?;
+        let mut stream = timeout(connect_timeout, self.outbound.connect(host, port))
+            .await
+            .map_err(|_| anyhow!("Timeout while connecting to {}", self.upstream))??;
+        if let Some(proxy_header) = proxy_header {
+            stream.write_all(&proxy_header).await?;
+        }
+
+        if self.upstream.scheme() == "https" {
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str(host)
+                .map_err(|_| anyhow!("Upstream host {} is not a valid DNS name", host))?;
+            let stream = TlsConnector::from(self.tls_config.clone())
+                .connect(dns_name, stream)
+                .await?;
+            handshake(stream).await
+        } else {
+            handshake(stream).await
+        }
+    }
+
+    async fn proxy_call(
+        self,
+        request: Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+        upstream: Rc<Mutex<Option<SendRequest<Bytes>>>>,
+        peer_addr: Option<SocketAddr>,
+        listener: ListenerInfo,
+    ) {
+        let args = &self.args;
+        let remote = client_ip(
+            &args.trusted_proxies,
+            peer_addr.map(|p| p.ip()),
+            &request.headers().clone().into(),
+        )
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+        // gRPC calls look like POST /package.Service/Method
+        let (service, method) = request
+            .uri()
+            .path()
+            .trim_start_matches('/')
+            .split_once('/')
+            .unwrap_or((request.uri().path(), ""));
+        let (service, method) = (service.to_string(), method.to_string());
+        let upstream_uri = upstream_uri(&self.args, &self.upstream, &request);
+
+        let incoming_call_log = log_incoming_grpc_call(&remote, &service, &method, args.verbose);
+        let upstream_call_log =
+            log_upstream_grpc_call(&upstream_uri, &service, &method, args.verbose);
+
+        let caller = Caller {
+            peer_addr,
+            remote: &remote,
+            listener,
+        };
+        let (status, message) = match self
+            .forward(request, &mut respond, &upstream, &upstream_uri, &caller)
+            .await
+        {
+            Ok((status, message)) => (status, message),
+            Err(e) => {
+                let message = e.to_string();
+                send_error(&mut respond, &message);
+                (Some(GRPC_STATUS_UNAVAILABLE.to_string()), Some(message))
+            }
+        };
+
+        let status_log =
+            log_grpc_status(&remote, status.as_deref(), message.as_deref(), args.verbose);
+        info!(
+            "{incoming_call}\n{upstream_call}\n{status}",
+            incoming_call = incoming_call_log,
+            upstream_call = upstream_call_log,
+            status = status_log
+        );
+    }
+
+    /// Forward a single call and return the gRPC status and message sent by the upstream.
+    async fn forward(
+        &self,
+        request: Request<RecvStream>,
+        respond: &mut SendResponse<Bytes>,
+        upstream: &Mutex<Option<SendRequest<Bytes>>>,
+        upstream_uri: &str,
+        caller: &Caller<'_>,
+    ) -> Result<(Option<String>, Option<String>)> {
+        let args = &self.args;
+        let Caller {
+            peer_addr,
+            remote,
+            listener,
+        } = *caller;
+        let peer_trusted = is_trusted(&args.trusted_proxies, peer_addr.map(|p| p.ip()));
+        let peer = peer_addr
+            .map(|p| p.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let (parts, request_body) = request.into_parts();
+        let host = parts
+            .uri
+            .authority()
+            .map(|authority| authority.as_str())
+            .unwrap_or("");
+
+        let mut upstream_request = Request::builder()
+            .method(parts.method.clone())
+            .uri(upstream_uri)
+            .version(Version::HTTP_2)
+            .body(())?;
+        let headers = upstream_request.headers_mut();
+        copy_headers(&parts.headers, headers);
+        if !peer_trusted {
+            headers.remove("x-real-ip");
+        }
+        for (header_name, header_value) in forwarding_headers(
+            &parts.headers.clone().into(),
+            &peer,
+            peer_trusted,
+            &args.listen.ip().to_string(),
+            host,
+            listener.scheme(),
+            parts.version,
+        ) {
+            headers.insert(header_name, HeaderValue::from_str(&header_value)?);
+        }
+
+        // Apply header options for upstream server request.
+        let request_id = request_id();
+        let vars = RequestVars {
+            client_ip: remote,
+            request_id: &request_id,
+            host,
+            scheme: listener.scheme(),
+        };
+        HeaderRules::upstream(args).apply_http(headers, &vars);
+
+        let mut send_request = self.upstream(upstream, peer_addr).await?;
+        let (response, upstream_body) =
+            send_request.send_request(upstream_request, request_body.is_end_stream())?;
+        if !request_body.is_end_stream() {
+            // Requests may be streamed while the response is already being received.
+            actix_rt::spawn(async move {
+                if let Err(e) = pipe(request_body, upstream_body).await {
+                    debug!("Failed to forward gRPC request body: {}", e);
+                }
+            });
+        }
+
+        let (parts, response_body) = response.await?.into_parts();
+        let mut outgoing_response = Response::builder().status(parts.status).body(())?;
+        let headers = outgoing_response.headers_mut();
+        copy_headers(&parts.headers, headers);
+
+        // Apply header options for outgoing response.
+        HeaderRules::response(args).apply_http(headers, &vars);
+
+        // "Trailers-Only" responses carry the status in the headers.
+        let end_of_stream = response_body.is_end_stream();
+        let outgoing_body = respond.send_response(outgoing_response, end_of_stream)?;
+        if end_of_stream {
+            return Ok(grpc_status(&parts.headers));
+        }
+        let trailers = pipe(response_body, outgoing_body).await?;
+        Ok(trailers.as_ref().map(grpc_status).unwrap_or((None, None)))
+    }
+}
+
+/// Perform the HTTP/2 handshake with the upstream and drive the connection in the background.
+async fn handshake<S>(io: S) -> Result<SendRequest<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let (send_request, connection) = h2::client::handshake(io).await?;
+    actix_rt::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("HTTP/2 connection to upstream failed: {}", e);
+        }
+    });
+    Ok(send_request)
+}
+
+/// Build the upstream URI for `request`, keeping its query.
+fn upstream_uri<T>(args: &CliArgs, upstream: &Url, request: &Request<T>) -> String {
+    upstream_url(args, upstream, request.uri().path(), request.uri().query()).to_string()
+}
+
+/// Copy all headers except for hop-by-hop ones, which are not allowed in HTTP/2 anyway.
+///
+/// `TE: trailers` is the exception, as it's allowed in HTTP/2 and required by gRPC.
+fn copy_headers(from: &HeaderMap, to: &mut HeaderMap) {
+    let connection_options = connection_options(from.get_all(CONNECTION));
+    for (header_name, header_value) in from {
+        if (is_hop_by_hop(header_name) && !(header_name == TE && header_value == "trailers"))
+            || connection_options.contains(header_name)
+        {
+            continue;
+        }
+        to.append(header_name, header_value.clone());
+    }
+}
+
+/// Extract `grpc-status` and `grpc-message` from headers or trailers.
+fn grpc_status(headers: &HeaderMap) -> (Option<String>, Option<String>) {
+    let get = |name: &str| {
+        headers
+            .get(name)
+            .map(|value| value.to_str().unwrap_or("<unprintable>").to_string())
+    };
+    (get("grpc-status"), get("grpc-message"))
+}
+
+/// Respond to a call which couldn't be forwarded.
+fn send_error(respond: &mut SendResponse<Bytes>, message: &str) {
+    // The response might already have been sent, in which case resetting the stream is all we
+    // can do.
+    if respond
+        .send_response(error_response(message), true)
+        .is_err()
+    {
+        respond.send_reset(h2::Reason::INTERNAL_ERROR);
+    }
+}
+
+/// A "Trailers-Only" response telling the client that the upstream is unavailable.
+fn error_response(message: &str) -> Response<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = StatusCode::OK;
+    let headers = response.headers_mut();
+    headers.insert("content-type", HeaderValue::from_static("application/grpc"));
+    headers.insert(
+        "grpc-status",
+        HeaderValue::from_static(GRPC_STATUS_UNAVAILABLE),
+    );
+    if let Ok(message) = HeaderValue::from_str(message) {
+        headers.insert("grpc-message", message);
+    }
+    response
+}
+
+/// Stream a body from `from` to `to`, followed by its trailers (if any) which are returned.
+async fn pipe(
+    mut from: RecvStream,
+    mut to: SendStream<Bytes>,
+) -> Result<Option<HeaderMap>, h2::Error> {
+    while let Some(data) = from.data().await {
+        let mut data = data?;
+        from.flow_control().release_capacity(data.len())?;
+        while !data.is_empty() {
+            to.reserve_capacity(data.len());
+            let capacity = match poll_fn(|cx| to.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => return Err(h2::Reason::CANCEL.into()),
+            };
+            let len = capacity.min(data.len());
+            to.send_data(data.split_to(len), false)?;
+        }
+    }
+    let trailers = from.trailers().await?;
+    match &trailers {
+        Some(trailers) => to.send_trailers(trailers.clone())?,
+        None => to.send_data(Bytes::new(), true)?,
+    }
+    Ok(trailers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderName;
+    use pretty_assertions::assert_eq;
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    fn pairs(headers: &HeaderMap) -> Vec<(&str, &str)> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_copy_headers() {
+        let from = headers(&[
+            ("content-type", "application/grpc"),
+            ("te", "trailers"),
+            ("connection", "keep-alive, upgrade, x-secret"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-connection", "keep-alive"),
+            ("upgrade", "h2c"),
+            ("x-secret", "1"),
+            ("grpc-timeout", "1S"),
+            ("x-multi", "1"),
+            ("x-multi", "2"),
+        ]);
+        let mut to = headers(&[("x-existing", "1")]);
+        copy_headers(&from, &mut to);
+        assert_eq!(
+            pairs(&to),
+            [
+                ("x-existing", "1"),
+                ("content-type", "application/grpc"),
+                ("te", "trailers"),
+                ("grpc-timeout", "1S"),
+                ("x-multi", "1"),
+                ("x-multi", "2"),
+            ]
+        );
+
+        // Only `TE: trailers` may be sent over HTTP/2.
+        let mut to = HeaderMap::new();
+        copy_headers(&headers(&[("te", "gzip")]), &mut to);
+        assert!(to.is_empty());
+    }
+
+    #[test]
+    fn test_grpc_status() {
+        let trailers = headers(&[
+            ("grpc-status", "5"),
+            ("grpc-message", "Not found"),
+            ("x-trailer", "1"),
+        ]);
+        assert_eq!(
+            grpc_status(&trailers),
+            (Some("5".to_string()), Some("Not found".to_string()))
+        );
+        assert_eq!(
+            grpc_status(&headers(&[("grpc-status", "0")])),
+            (Some("0".to_string()), None)
+        );
+        assert_eq!(grpc_status(&HeaderMap::new()), (None, None));
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert(
+            "grpc-message",
+            HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap(),
+        );
+        assert_eq!(
+            grpc_status(&trailers),
+            (None, Some("<unprintable>".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_error_response() {
+        let response = error_response("Connection refused");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            pairs(response.headers()),
+            [
+                ("content-type", "application/grpc"),
+                ("grpc-status", GRPC_STATUS_UNAVAILABLE),
+                ("grpc-message", "Connection refused"),
+            ]
+        );
+
+        // Messages which can't be sent as a header value are left out.
+        let response = error_response("Connection\nrefused");
+        assert_eq!(response.headers().get("grpc-status").unwrap(), "14");
+        assert!(response.headers().get("grpc-message").is_none());
+    }
+}
@@ -0,0 +1,383 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_codec::Framed;
+use actix_http::body::{BodySize, MessageBody};
+use actix_http::client::{Connect, ConnectError, Connection, Protocol, SendRequestError};
+use actix_http::h1::ClientCodec;
+use actix_http::http::HeaderMap;
+use actix_http::{Payload, RequestHeadType, ResponseHead};
+use actix_service::Service;
+use actix_web::web::Bytes;
+use futures::future::{poll_fn, LocalBoxFuture};
+use futures::pin_mut;
+use h2::client::SendRequest;
+use h2::SendStream;
+use http::header::{HeaderValue, CONTENT_LENGTH};
+use http::{Method, Request, Version};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::header_order::values_in_order;
+use crate::hop_by_hop::is_connection_specific;
+use crate::outbound::Outbound;
+use crate::unix;
+
+/// Connector speaking HTTP/2 with prior knowledge (h2c) to plain text upstreams.
+///
+/// awc only ever speaks HTTP/1.1 over plain text connections so we establish those connections
+/// ourselves. Every connection is kept open and shared by all requests to the same upstream.
+///
+/// If `socket` is given, all connections are made to that Unix domain socket instead, otherwise
+/// they are made using `outbound`. If `proxy_header` is given, it's sent at the start of every
+/// connection.
+#[derive(Clone)]
+pub struct H2cConnector {
+    timeout: Duration,
+    socket: Option<PathBuf>,
+    outbound: Rc<Outbound>,
+    proxy_header: Option<Rc<[u8]>>,
+    connections: Rc<RefCell<HashMap<String, SendRequest<Bytes>>>>,
+}
+
+impl H2cConnector {
+    pub fn new(
+        timeout: Duration,
+        socket: Option<PathBuf>,
+        outbound: Outbound,
+        proxy_header: Option<Rc<[u8]>>,
+    ) -> Self {
+        H2cConnector {
+            timeout,
+            socket,
+            outbound: Rc::new(outbound),
+            proxy_header,
+            connections: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+}
+
+/// Perform the HTTP/2 handshake on `io` and drive the connection in the background.
+///
+/// `proxy_header` is sent before anything else.
+async fn handshake<S>(
+    mut io: S,
+    proxy_header: Option<Rc<[u8]>>,
+) -> Result<SendRequest<Bytes>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    if let Some(proxy_header) = proxy_header {
+        io.write_all(&proxy_header)
+            .await
+            .map_err(ConnectError::Io)?;
+    }
+    let (send_request, connection) = h2::client::handshake(io).await.map_err(ConnectError::H2)?;
+    actix_rt::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("h2c connection to upstream failed: {}", e);
+        }
+    });
+    Ok(send_request)
+}
+
+impl Service for H2cConnector {
+    type Request = Connect;
+    type Response = H2cConnection;
+    type Error = ConnectError;
+    type Future = LocalBoxFuture<'static, Result<H2cConnection, ConnectError>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Connect) -> Self::Future {
+        let connect_timeout = self.timeout;
+        let socket = self.socket.clone();
+        let outbound = self.outbound.clone();
+        let proxy_header = self.proxy_header.clone();
+        let connections = self.connections.clone();
+        Box::pin(async move {
+            let host = req.uri.host().ok_or(ConnectError::Unresolved)?;
+            let port = req.uri.port_u16().unwrap_or(80);
+            let authority = format!("{}:{}", host, port);
+
+            // Reuse an existing connection unless it has been closed in the meantime.
+            let cached = connections.borrow().get(&authority).cloned();
+            if let Some(send_request) = cached {
+                if let Ok(send_request) = send_request.ready().await {
+                    return Ok(H2cConnection { send_request });
+                }
+                connections.borrow_mut().remove(&authority);
+            }
+
+            let send_request = match socket {
+                Some(socket) => {
+                    let stream = timeout(connect_timeout, unix::connect(&socket))
+                        .await
+                        .map_err(|_| ConnectError::Timeout)?
+                        .map_err(ConnectError::Io)?;
+                    handshake(stream, proxy_header).await?
+                }
+                None => {
+                    let stream = timeout(connect_timeout, outbound.connect(host, port))
+                        .await
+                        .map_err(|_| ConnectError::Timeout)?
+                        .map_err(ConnectError::Io)?;
+                    handshake(stream, proxy_header).await?
+                }
+            };
+            connections
+                .borrow_mut()
+                .insert(authority, send_request.clone());
+            Ok(H2cConnection { send_request })
+        })
+    }
+}
+
+/// A single request on an h2c connection established by `H2cConnector`.
+pub struct H2cConnection {
+    send_request: SendRequest<Bytes>,
+}
+
+impl Connection for H2cConnection {
+    type Io = TcpStream;
+    type Future = LocalBoxFuture<'static, Result<(ResponseHead, Payload), SendRequestError>>;
+    type TunnelFuture = LocalBoxFuture<
+        'static,
+        Result<(ResponseHead, Framed<TcpStream, ClientCodec>), SendRequestError>,
+    >;
+
+    fn protocol(&self) -> Protocol {
+        Protocol::Http2
+    }
+
+    fn send_request<B: MessageBody + 'static, H: Into<RequestHeadType>>(
+        self,
+        head: H,
+        body: B,
+    ) -> Self::Future {
+        Box::pin(send_request(self.send_request, head.into(), body))
+    }
+
+    fn open_tunnel<H: Into<RequestHeadType>>(self, _head: H) -> Self::TunnelFuture {
+        Box::pin(async { Err(SendRequestError::TunnelNotSupported) })
+    }
+}
+
+/// Copy the headers of a request and the `extra_headers` replacing some of them to `to`.
+///
+/// Connection-specific headers are not allowed in HTTP/2 and the content length is set from the
+/// body instead.
+fn copy_headers(headers: &HeaderMap, extra_headers: &HeaderMap, to: &mut http::HeaderMap) {
+    let headers = headers
+        .keys()
+        .filter(|name| !extra_headers.contains_key(*name))
+        .map(|name| (name, headers))
+        .chain(extra_headers.keys().map(|name| (name, extra_headers)));
+    for (name, headers) in headers {
+        if is_connection_specific(name) || *name == CONTENT_LENGTH {
+            continue;
+        }
+        for value in values_in_order(headers, name.as_str()) {
+            to.append(name, value.clone());
+        }
+    }
+}
+
+async fn send_request<B: MessageBody>(
+    mut send_request: SendRequest<Bytes>,
+    head: RequestHeadType,
+    body: B,
+) -> Result<(ResponseHead, Payload), SendRequestError> {
+    let head_request = head.as_ref().method == Method::HEAD;
+    let length = body.size();
+    let eof = matches!(
+        length,
+        BodySize::None | BodySize::Empty | BodySize::Sized(0)
+    );
+
+    let mut req = Request::new(());
+    *req.uri_mut() = head.as_ref().uri.clone();
+    *req.method_mut() = head.as_ref().method.clone();
+    *req.version_mut() = Version::HTTP_2;
+
+    match length {
+        BodySize::Empty => {
+            req.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+        }
+        BodySize::Sized(len) => {
+            req.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+        BodySize::None | BodySize::Stream => {}
+    }
+
+    let (head, extra_headers) = match head {
+        RequestHeadType::Owned(head) => (RequestHeadType::Owned(head), HeaderMap::new()),
+        RequestHeadType::Rc(head, extra_headers) => (
+            RequestHeadType::Rc(head, None),
+            extra_headers.unwrap_or_else(HeaderMap::new),
+        ),
+    };
+    copy_headers(&head.as_ref().headers, &extra_headers, req.headers_mut());
+
+    poll_fn(|cx| send_request.poll_ready(cx)).await?;
+    let (response, send_stream) = send_request.send_request(req, eof)?;
+    if !eof {
+        send_body(body, send_stream).await?;
+    }
+    let (parts, body) = response.await?.into_parts();
+
+    let mut head = ResponseHead::new(parts.status);
+    head.version = parts.version;
+    head.headers = parts.headers.into();
+    let payload = if head_request {
+        Payload::None
+    } else {
+        body.into()
+    };
+    Ok((head, payload))
+}
+
+async fn send_body<B: MessageBody>(
+    body: B,
+    mut send_stream: SendStream<Bytes>,
+) -> Result<(), SendRequestError> {
+    pin_mut!(body);
+    while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+        let mut chunk = chunk?;
+        while !chunk.is_empty() {
+            send_stream.reserve_capacity(chunk.len());
+            let capacity = match poll_fn(|cx| send_stream.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => return Ok(()),
+            };
+            let len = capacity.min(chunk.len());
+            send_stream.send_data(chunk.split_to(len), false)?;
+        }
+    }
+    send_stream.send_data(Bytes::new(), true)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use pretty_assertions::assert_eq;
+    use tokio::net::TcpListener;
+
+    use crate::args::CliArgs;
+
+    /// An h2c server handing out its connections, which aren't served but stay open until they
+    /// are dropped.
+    async fn server() -> (
+        http::Uri,
+        mpsc::UnboundedReceiver<h2::server::Connection<TcpStream, Bytes>>,
+    ) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let (sender, receiver) = mpsc::unbounded();
+        actix_rt::spawn(async move {
+            while let Ok((io, _)) = listener.accept().await {
+                let connection = h2::server::handshake(io).await.unwrap();
+                if sender.unbounded_send(connection).is_err() {
+                    break;
+                }
+            }
+        });
+        (uri, receiver)
+    }
+
+    #[test]
+    fn test_copy_headers() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("connection", "upgrade"),
+            ("upgrade", "websocket"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+            ("content-length", "5"),
+            ("x-multi", "1"),
+            ("x-multi", "2"),
+            ("x-multi", "3"),
+            ("x-replaced", "1"),
+        ] {
+            headers.append(
+                http::header::HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        let mut extra_headers = HeaderMap::new();
+        extra_headers.insert(
+            http::header::HeaderName::from_static("x-replaced"),
+            HeaderValue::from_static("2"),
+        );
+
+        let mut to = http::HeaderMap::new();
+        copy_headers(&headers, &extra_headers, &mut to);
+        let mut pairs = to
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        // Different names come in no particular order from actix-http's `HeaderMap`.
+        pairs.sort_by_key(|(name, _)| *name);
+        assert_eq!(
+            pairs,
+            [
+                ("x-multi", "1"),
+                ("x-multi", "2"),
+                ("x-multi", "3"),
+                ("x-replaced", "2"),
+            ]
+        );
+    }
+
+    fn connector() -> H2cConnector {
+        let args = CliArgs::parse_from(["proxyboi", "http://localhost:3000"]);
+        H2cConnector::new(Duration::from_secs(5), None, Outbound::new(&args), None)
+    }
+
+    #[actix_rt::test]
+    async fn test_connection_reuse() {
+        let (uri, mut connections) = server().await;
+        let mut connector = connector();
+        let connect = || Connect {
+            uri: uri.clone(),
+            addr: None,
+        };
+
+        connector.call(connect()).await.unwrap();
+        let first = connections.next().await.unwrap();
+
+        // Requests share the open connection.
+        connector.call(connect()).await.unwrap();
+        connector.call(connect()).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(connections.try_next().is_err());
+        assert_eq!(connector.connections.borrow().len(), 1);
+
+        // Once the upstream closes the connection, a new one is established.
+        drop(first);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        connector.call(connect()).await.unwrap();
+        let _second = connections.next().await.unwrap();
+        assert_eq!(connector.connections.borrow().len(), 1);
+        connector.call(connect()).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(connections.try_next().is_err());
+    }
+}
@@ -1,102 +1,224 @@
+use actix_web::http::{HeaderMap, StatusCode, Version};
 use actix_web::{client::Client, web, HttpRequest, HttpResponse};
-use log::info;
+use log::{info, warn};
+use rustls::ClientConfig;
 
 use crate::{
     args::CliArgs,
+    body_filter::filter_body,
+    client::{upstream_client, ConnectionClient, ForwardProxyClient},
+    client_ip::{client_ip, is_trusted, request_host},
     error::ProxyboiError,
-    forwarded_header::ForwardedHeader,
+    forward_proxy::destination_url,
+    forwarded_header::{header_values, ForwardedHeader},
+    header_rules::{request_id, HeaderRules, RequestVars},
+    hop_by_hop::remove_hop_by_hop_headers,
     logging::{
         log_incoming_request, log_outgoing_response, log_upstream_request, log_upstream_response,
     },
+    proxy_protocol::{v2_header, ProxiedAddrs},
+    response_headers::{CookieRewriter, LocationRewriter},
+    rewrite::{rewrite, Rewrite},
+    server::ListenerInfo,
+    upstream_path::upstream_url,
 };
 
+/// Headers telling the upstream about the original request and the proxies it passed through.
+///
+/// Values from previous proxies found in `headers` are preserved if `peer` is a trusted proxy and
+/// discarded otherwise, as anyone else could have made them up.
+pub fn forwarding_headers(
+    headers: &HeaderMap,
+    peer: &str,
+    peer_trusted: bool,
+    listen: &str,
+    host: &str,
+    protocol: &str,
+    version: Version,
+) -> Vec<(&'static str, String)> {
+    let previous_values = |name| {
+        if peer_trusted {
+            header_values(headers, name)
+        } else {
+            vec![]
+        }
+    };
+    let forwarded = previous_values("forwarded");
+
+    let forwarded_header = ForwardedHeader::from_info(peer, listen, forwarded, host, protocol);
+    let via = if let Some(via) = headers.get("via").map(|x| x.to_str().unwrap_or("")) {
+        format!(
+            "{previous_via}, {version:?} proxyboi",
+            previous_via = via,
+            version = version
+        )
+    } else {
+        format!("{version:?} proxyboi", version = version)
+    };
+
+    // The X-Forwarded-For header is much simpler to handle :)
+    let mut x_forwarded_for = previous_values("x-forwarded-for");
+    x_forwarded_for.push(peer);
+    let x_forwarded_for_appended = x_forwarded_for.join(", ");
+
+    vec![
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Forwarded
+        ("forwarded", forwarded_header.to_string()),
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/X-Forwarded-Proto
+        ("x-forwarded-proto", protocol.to_string()),
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/X-Forwarded-Host
+        ("x-forwarded-host", host.to_string()),
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/X-Forwarded-For
+        ("x-forwarded-for", x_forwarded_for_appended),
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Via
+        ("via", via),
+    ]
+}
+
 pub async fn forward(
     incoming_request: HttpRequest,
     body: web::Bytes,
     args: web::Data<CliArgs>,
     client: web::Data<Client>,
+    tls_config: web::Data<ClientConfig>,
+    listener: web::Data<ListenerInfo>,
+    forward_proxy_client: web::Data<ForwardProxyClient>,
 ) -> Result<HttpResponse, ProxyboiError> {
-    let incoming_request_log = log_incoming_request(&incoming_request, args.verbose);
+    let peer_ip = incoming_request.head().peer_addr.map(|p| p.ip());
+    let peer_trusted = is_trusted(&args.trusted_proxies, peer_ip);
+    let remote = client_ip(&args.trusted_proxies, peer_ip, incoming_request.headers())
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let incoming_request_log = log_incoming_request(&incoming_request, &remote, args.verbose);
 
     // Figure out new URL like such:
     // Old URL: http://localhost:8080/foo?bar=1
-    // New URL: https://0.0.0.0:8081/foo?bar=1
-    // So in effect, we have to change `protocol`, `host`, `port` and keep `path` and `query`.
-    let mut new_url = args.upstream.clone();
-    new_url.set_path(incoming_request.uri().path());
-    new_url.set_query(incoming_request.uri().query());
-
-    let conn_info = &incoming_request.connection_info().clone();
-    let protocol = conn_info.scheme();
+    // New URL: https://0.0.0.0:8081/base/foo?bar=1
+    // So in effect, we have to change `protocol`, `host`, `port`, join the upstream's path with
+    // ours and keep `query`.
+    //
+    // In forward proxy mode, absolute-form requests go wherever they ask for instead. HTTP/2
+    // requests always carry an authority, so this only applies to HTTP/1.
+    let forward_proxied = args.forward_proxy
+        && incoming_request.version() < Version::HTTP_2
+        && incoming_request.uri().authority().is_some();
+    let new_url = if forward_proxied {
+        match destination_url(&args, incoming_request.uri()).await {
+            Ok(url) => url,
+            Err((status, reason)) => {
+                warn!("Refused to proxy request from {}: {}", remote, reason);
+                return Ok(HttpResponse::build(status).finish());
+            }
+        }
+    } else {
+        let uri = incoming_request.uri();
+        let path_and_query = uri
+            .path_and_query()
+            .map(|path_and_query| path_and_query.as_str())
+            .unwrap_or("/");
+        let (path, query) = match rewrite(&args.rewrites, path_and_query) {
+            Some(Rewrite::Redirect { status, location }) => {
+                info!(
+                    "{}\nRedirecting to {} ({})",
+                    incoming_request_log, location, status
+                );
+                return Ok(HttpResponse::build(status)
+                    .header("location", location)
+                    .finish());
+            }
+            Some(Rewrite::Internal { path, query }) => (path, query),
+            None => (
+                uri.path().to_string(),
+                uri.query().map(|query| query.to_string()),
+            ),
+        };
+        match args.upstream.as_ref() {
+            Some(upstream) => upstream_url(&args, upstream, &path, query.as_deref()),
+            None => {
+                warn!(
+                    "Request for {} is not in absolute-form and there is no upstream to send it to",
+                    incoming_request.uri()
+                );
+                return Ok(HttpResponse::BadRequest().finish());
+            }
+        }
+    };
+
+    let protocol = listener.scheme();
     let version = incoming_request.version();
-    let host = conn_info.host();
+    let host = request_host(
+        peer_trusted,
+        incoming_request.uri(),
+        incoming_request.headers(),
+    )
+    .unwrap_or_else(|| incoming_request.app_config().host().to_string());
+    let host = host.as_str();
 
-    let peer = incoming_request
-        .head()
-        .peer_addr
-        .map(|p| p.ip().to_string())
+    let peer = peer_ip
+        .map(|ip| ip.to_string())
         .unwrap_or_else(|| "unknown".to_string());
 
-    let forwarded = incoming_request
-        .headers()
-        .get("forwarded")
-        .map(|x| x.to_str().unwrap_or(""))
-        .unwrap_or("");
+    // The PROXY protocol header describes a whole connection, so connections to the upstream can
+    // only be shared by requests on the same client connection.
+    let proxy_protocol_client;
+    let client = if forward_proxied {
+        &forward_proxy_client.0
+    } else if args.upstream_proxy_protocol {
+        let new_client = || {
+            let addrs = incoming_request
+                .head()
+                .peer_addr
+                .map(|source| ProxiedAddrs {
+                    source,
+                    destination: args.listen,
+                });
+            upstream_client(&args, tls_config.into_inner(), Some(v2_header(addrs)))
+        };
+        // HTTP/3 requests don't come with a client for their connection.
+        let connection_client = incoming_request
+            .extensions()
+            .get::<ConnectionClient>()
+            .cloned();
+        proxy_protocol_client = match connection_client {
+            Some(connection_client) => connection_client.get_or_insert_with(new_client),
+            None => new_client(),
+        };
+        &proxy_protocol_client
+    } else {
+        client.get_ref()
+    };
 
-    let forwarded_header = ForwardedHeader::from_info(
+    let mut upstream_req = client
+        .request_from(new_url.as_str(), incoming_request.head())
+        .no_decompress();
+    // The upstream client can't tunnel an upgraded connection, so the upstream answers requests
+    // for an upgrade like any other request.
+    remove_hop_by_hop_headers(upstream_req.headers_mut(), false);
+    if !peer_trusted {
+        upstream_req.headers_mut().remove("x-real-ip");
+    }
+    for (header_name, header_value) in forwarding_headers(
+        incoming_request.headers(),
         &peer,
+        peer_trusted,
         &args.listen.ip().to_string(),
-        forwarded,
         host,
         protocol,
-    );
-    let via = if let Some(via) = incoming_request
-        .headers()
-        .get("via")
-        .map(|x| x.to_str().unwrap_or(""))
-    {
-        format!(
-            "{previous_via}, {version:?} proxyboi",
-            previous_via = via,
-            version = version
-        )
-    } else {
-        format!("{version:?} proxyboi", version = version)
-    };
+        version,
+    ) {
+        upstream_req = upstream_req.set_header(header_name, header_value);
+    }
 
-    // The X-Forwarded-For header is much simpler to handle :)
-    let x_forwarded_for = incoming_request
-        .headers()
-        .get("x-forwarded-for")
-        .map(|x| x.to_str().unwrap_or(""));
-    let x_forwarded_for_appended = if let Some(x_forwarded_for) = x_forwarded_for {
-        format!("{}, {}", x_forwarded_for, peer)
-    } else {
-        peer
+    // Apply header options for upstream server request.
+    let request_id = request_id();
+    let vars = RequestVars {
+        client_ip: &remote,
+        request_id: &request_id,
+        host,
+        scheme: protocol,
     };
-
-    let mut upstream_req = client
-        .request_from(new_url.as_str(), incoming_request.head())
-        .no_decompress()
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Forwarded
-        .set_header("forwarded", forwarded_header.to_string())
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/X-Forwarded-Proto
-        .set_header("x-forwarded-proto", protocol)
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/X-Forwarded-Host
-        .set_header("x-forwarded-host", host)
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/X-Forwarded-For
-        .set_header("x-forwarded-for", x_forwarded_for_appended)
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Via
-        .set_header("via", via);
-
-    // Insert additional headers for upstream server request.
-    for additional_header in &args.upstream_headers {
-        let (header_name, header_value) = additional_header
-            .iter()
-            .next()
-            .expect("Expected to find a header here but there was none");
-        upstream_req = upstream_req.set_header(header_name, header_value.clone());
-    }
+    HeaderRules::upstream(&args).apply(upstream_req.headers_mut(), &vars);
 
     let upstream_request_log = log_upstream_request(&upstream_req, args.verbose);
 
@@ -105,36 +227,69 @@ pub async fn forward(
     let upstream_response_log =
         log_upstream_response(&upstream_resp, new_url.as_str(), args.verbose);
 
+    let location_rewriter = match &args.upstream {
+        Some(upstream) if !forward_proxied && !args.no_rewrite_location => {
+            Some(LocationRewriter::new(&args, upstream, protocol, host))
+        }
+        _ => None,
+    };
+    let cookie_rewriter = match &args.upstream {
+        Some(upstream) if !forward_proxied => Some(CookieRewriter::new(&args, upstream, host)),
+        _ => None,
+    };
+
+    let upstream_body = upstream_resp.body().await?;
+    let filtered_body = filter_body(
+        args.body_replacements
+            .iter()
+            .chain(&args.body_regex_replacements),
+        upstream_resp.headers(),
+        &upstream_body,
+    );
+
     let mut outgoing_resp_builder = HttpResponse::build(upstream_resp.status());
     for (header_name, header_value) in upstream_resp
         .headers()
         .iter()
-        // Remove `Connection` as per
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
-        .filter(|(h, _)| *h != "connection" && *h != "transfer-encoding")
+        // The length and entity tag of a filtered body are different from the original one.
+        .filter(|(h, _)| filtered_body.is_none() || (*h != "content-length" && *h != "etag"))
     {
-        outgoing_resp_builder.header(header_name, header_value.clone());
+        let header_value = location_rewriter
+            .as_ref()
+            .and_then(|rewriter| rewriter.rewrite_header(header_name, header_value))
+            .or_else(|| {
+                cookie_rewriter
+                    .as_ref()
+                    .and_then(|rewriter| rewriter.rewrite_header(header_name, header_value))
+            })
+            .unwrap_or_else(|| header_value.clone());
+        outgoing_resp_builder.header(header_name, header_value);
     }
 
-    // Insert additional headers for outgoing response.
-    for additional_header in &args.response_headers {
-        let (header_name, header_value) = additional_header
-            .iter()
-            .next()
-            .expect("Expected to find a header here but there was none");
-        outgoing_resp_builder.header(header_name, header_value.clone());
+    // Let clients know that they can switch to HTTP/3.
+    if args.http3 && listener.secure && version != Version::HTTP_3 {
+        outgoing_resp_builder.header(
+            "alt-svc",
+            format!("h3=\":{}\"; ma=86400", args.listen.port()),
+        );
     }
 
-    let outgoing_resp = outgoing_resp_builder.body(upstream_resp.body().await?);
+    let mut outgoing_resp = match filtered_body {
+        Some(filtered_body) => outgoing_resp_builder.body(filtered_body),
+        None => outgoing_resp_builder.body(upstream_body),
+    };
 
-    let outgoing_response_log = log_outgoing_response(
-        &outgoing_resp,
-        incoming_request
-            .connection_info()
-            .realip_remote_addr()
-            .unwrap_or("unknown"),
-        args.verbose,
+    // Remove `Connection` and the like as per
+    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
+    remove_hop_by_hop_headers(
+        outgoing_resp.headers_mut(),
+        upstream_resp.status() == StatusCode::SWITCHING_PROTOCOLS,
     );
+
+    // Apply header options for outgoing response.
+    HeaderRules::response(&args).apply(outgoing_resp.headers_mut(), &vars);
+
+    let outgoing_response_log = log_outgoing_response(&outgoing_resp, &remote, args.verbose);
     info!(
         "{incoming_req}\n{upstream_req}\n{upstream_resp}\n{outgoing_resp}",
         incoming_req = incoming_request_log,
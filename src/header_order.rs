@@ -0,0 +1,37 @@
+use actix_web::http::{HeaderMap, HeaderValue};
+
+/// All values of the header `name` in the order they were received or added.
+///
+/// actix-http's `HeaderMap` puts the second value of a header in front of the first one, which
+/// this undoes. Copying values from one `HeaderMap` into another with `append()` swaps them back
+/// by itself, so this is needed where the order matters or the values leave actix-http.
+pub fn values_in_order<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a HeaderValue> {
+    let mut values = headers.get_all(name).collect::<Vec<_>>();
+    if values.len() > 1 {
+        values.swap(0, 1);
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderName;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_values_in_order() {
+        let mut headers = HeaderMap::new();
+        for value in ["for=192.0.2.1", "for=192.0.2.2", "for=192.0.2.3"] {
+            headers.append(
+                HeaderName::from_static("forwarded"),
+                HeaderValue::from_static(value),
+            );
+        }
+        assert_eq!(
+            values_in_order(&headers, "forwarded"),
+            ["for=192.0.2.1", "for=192.0.2.2", "for=192.0.2.3"]
+        );
+        assert!(values_in_order(&headers, "x-forwarded-for").is_empty());
+    }
+}
@@ -0,0 +1,413 @@
+use std::str::FromStr;
+
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+use log::warn;
+use regex::Regex;
+use ring::rand::SystemRandom;
+
+use crate::args::CliArgs;
+use crate::header_order::values_in_order;
+
+/// Parse a header name given on the command line.
+pub fn parse_header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.trim().to_lowercase().as_bytes()).map_err(|e| e.to_string())
+}
+
+/// Values of the variables which may be used in headers given on the command line, for one
+/// request.
+pub struct RequestVars<'a> {
+    pub client_ip: &'a str,
+    pub request_id: &'a str,
+    pub host: &'a str,
+    pub scheme: &'a str,
+}
+
+/// Generate a random ID for a request, to be used as `{request_id}`.
+pub fn request_id() -> String {
+    let id: [u8; 16] = ring::rand::generate(&SystemRandom::new())
+        .expect("Couldn't generate random request ID")
+        .expose();
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Part of a header value given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    ClientIp,
+    RequestId,
+    Host,
+    Scheme,
+    Env(String),
+}
+
+/// A header given on the command line as "NAME: VALUE".
+///
+/// The value may contain the variables `{client_ip}`, `{request_id}`, `{host}`, `{scheme}` and
+/// `{env:VAR}` which are expanded for every request. Anything else in braces is taken literally.
+#[derive(Debug, Clone)]
+pub struct HeaderTemplate {
+    name: HeaderName,
+    parts: Vec<TemplatePart>,
+}
+
+impl HeaderTemplate {
+    pub fn new(name: &str, value: &str) -> Result<Self, String> {
+        let name = parse_header_name(name)?;
+        let mut parts = vec![];
+        let mut rest = value.trim();
+        while !rest.is_empty() {
+            let variable = rest.find('{').and_then(|start| {
+                let end = start + rest[start..].find('}')?;
+                let part = match &rest[start + 1..end] {
+                    "client_ip" => TemplatePart::ClientIp,
+                    "request_id" => TemplatePart::RequestId,
+                    "host" => TemplatePart::Host,
+                    "scheme" => TemplatePart::Scheme,
+                    variable => TemplatePart::Env(variable.strip_prefix("env:")?.to_string()),
+                };
+                Some((start, end, part))
+            });
+            match variable {
+                Some((start, end, part)) => {
+                    if start > 0 {
+                        parts.push(TemplatePart::Literal(rest[..start].to_string()));
+                    }
+                    parts.push(part);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    // No variable starts at the next brace, so take everything up to the one
+                    // after it literally.
+                    let literal_end = rest[1..].find('{').map_or(rest.len(), |next| next + 1);
+                    match parts.last_mut() {
+                        Some(TemplatePart::Literal(literal)) => {
+                            literal.push_str(&rest[..literal_end])
+                        }
+                        _ => parts.push(TemplatePart::Literal(rest[..literal_end].to_string())),
+                    }
+                    rest = &rest[literal_end..];
+                }
+            }
+        }
+
+        // Catch invalid characters early, variables can only be checked once they're expanded.
+        for part in &parts {
+            if let TemplatePart::Literal(literal) = part {
+                HeaderValue::from_str(literal).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(HeaderTemplate { name, parts })
+    }
+
+    /// The value of the header for a request, `None` if a variable expanded to something which
+    /// isn't allowed in headers.
+    fn expand(&self, vars: &RequestVars) -> Option<HeaderValue> {
+        let mut value = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => value.push_str(literal),
+                TemplatePart::ClientIp => value.push_str(vars.client_ip),
+                TemplatePart::RequestId => value.push_str(vars.request_id),
+                TemplatePart::Host => value.push_str(vars.host),
+                TemplatePart::Scheme => value.push_str(vars.scheme),
+                TemplatePart::Env(variable) => {
+                    value.push_str(&std::env::var(variable).unwrap_or_default())
+                }
+            }
+        }
+        match HeaderValue::from_str(&value) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!(
+                    "Not sending header {} with invalid value {:?}",
+                    self.name, value
+                );
+                None
+            }
+        }
+    }
+}
+
+/// A rule given to `--rewrite-upstream-header` or `--rewrite-response-header` in the format
+/// "NAME REGEX REPLACEMENT".
+///
+/// Matches of the regex in all values of the header are replaced, the replacement may refer to
+/// capture groups as `$1` or `${name}` and contain spaces.
+#[derive(Debug, Clone)]
+pub struct HeaderRewrite {
+    name: HeaderName,
+    pattern: Regex,
+    replacement: String,
+}
+
+impl FromStr for HeaderRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format_error =
+            || "Wrong header rewrite format (expected \"NAME REGEX REPLACEMENT\")".to_string();
+        let (name, rest) = s
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .ok_or_else(format_error)?;
+        let (pattern, replacement) = rest
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .ok_or_else(format_error)?;
+        Ok(HeaderRewrite {
+            name: parse_header_name(name)?,
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+impl HeaderRewrite {
+    fn apply(&self, headers: &mut HeaderMap) {
+        let values = values_in_order(headers, self.name.as_str())
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            return;
+        }
+        headers.remove(&self.name);
+        for value in values {
+            // Values which aren't valid strings before or after rewriting are kept as they are.
+            let rewritten = value
+                .to_str()
+                .ok()
+                .and_then(|value| {
+                    HeaderValue::from_str(&self.pattern.replace_all(value, &*self.replacement)).ok()
+                })
+                .unwrap_or(value);
+            headers.append(self.name.clone(), rewritten);
+        }
+    }
+}
+
+/// Header changes given on the command line for either requests to the upstream or responses to
+/// the client.
+///
+/// They are applied in the order of the fields: headers are removed first, then the remaining ones
+/// rewritten before the given headers are set (replacing existing values) or appended. Headers
+/// given to be set multiple times are all set.
+pub struct HeaderRules<'a> {
+    remove: &'a [HeaderName],
+    rewrite: &'a [HeaderRewrite],
+    set: &'a [HeaderTemplate],
+    append: &'a [HeaderTemplate],
+}
+
+impl<'a> HeaderRules<'a> {
+    /// Rules for requests to the upstream.
+    pub fn upstream(args: &'a CliArgs) -> Self {
+        HeaderRules {
+            remove: &args.remove_upstream_headers,
+            rewrite: &args.rewrite_upstream_headers,
+            set: &args.upstream_headers,
+            append: &args.append_upstream_headers,
+        }
+    }
+
+    /// Rules for responses to the client.
+    pub fn response(args: &'a CliArgs) -> Self {
+        HeaderRules {
+            remove: &args.remove_response_headers,
+            rewrite: &args.rewrite_response_headers,
+            set: &args.response_headers,
+            append: &args.append_response_headers,
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap, vars: &RequestVars) {
+        for name in self.remove {
+            headers.remove(name);
+        }
+        for rewrite in self.rewrite {
+            rewrite.apply(headers);
+        }
+        for header in self.set {
+            headers.remove(&header.name);
+        }
+        for header in self.set.iter().chain(self.append) {
+            if let Some(value) = header.expand(vars) {
+                headers.append(header.name.clone(), value);
+            }
+        }
+    }
+
+    /// Apply the rules to headers of the `http` crate as used for gRPC.
+    pub fn apply_http(&self, headers: &mut http::HeaderMap, vars: &RequestVars) {
+        let mut actix_headers = HeaderMap::from(std::mem::take(headers));
+        self.apply(&mut actix_headers, vars);
+        for name in actix_headers.keys() {
+            for value in values_in_order(&actix_headers, name.as_str()) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+
+    /// Sorted values of `name`, as `HeaderMap` doesn't keep the order of the first two values.
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        let mut values = headers
+            .get_all(name)
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        values.sort();
+        values
+    }
+
+    const VARS: RequestVars = RequestVars {
+        client_ip: "192.0.2.1",
+        request_id: "0123abcd",
+        host: "example.com",
+        scheme: "https",
+    };
+
+    fn expand(value: &str) -> String {
+        HeaderTemplate::new("x-test", value)
+            .unwrap()
+            .expand(&VARS)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_template() {
+        assert_eq!(expand("plain"), "plain");
+        assert_eq!(expand(" Basic a:b "), "Basic a:b");
+        assert_eq!(
+            expand("for={client_ip};id={request_id}"),
+            "for=192.0.2.1;id=0123abcd"
+        );
+        assert_eq!(expand("{scheme}://{host}/"), "https://example.com/");
+        assert_eq!(expand("{\"a\": {client_ip}}"), "{\"a\": 192.0.2.1}");
+        assert_eq!(expand("{unknown}{host}"), "{unknown}example.com");
+        assert_eq!(expand("{env:PROXYBOI_TEST_UNSET}"), "");
+        std::env::set_var("PROXYBOI_TEST_TOKEN", "secret");
+        assert_eq!(expand("Bearer {env:PROXYBOI_TEST_TOKEN}"), "Bearer secret");
+        assert!(HeaderTemplate::new("x-test", "bad\u{7f}").is_err());
+        assert!(HeaderTemplate::new("bad name", "value").is_err());
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(request_id().len(), 32);
+        assert_ne!(request_id(), request_id());
+    }
+
+    #[test]
+    fn test_parse_rewrite() {
+        let rewrite = "Server nginx/(\\S+) proxied nginx $1"
+            .parse::<HeaderRewrite>()
+            .unwrap();
+        assert_eq!(rewrite.name, "server");
+        assert_eq!(rewrite.replacement, "proxied nginx $1");
+        assert!("server nginx".parse::<HeaderRewrite>().is_err());
+        assert!("server (unclosed x".parse::<HeaderRewrite>().is_err());
+        assert!("bad\\name x y".parse::<HeaderRewrite>().is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        let args = CliArgs::parse_from([
+            "proxyboi",
+            "http://localhost:3000",
+            "--remove-response-header",
+            "Server",
+            "--remove-response-header",
+            "x-powered-by",
+            "--rewrite-response-header",
+            "set-cookie ^session= sid=",
+            "--response-header",
+            "cache-control: no-store",
+            "--response-header",
+            "x-served-for: {client_ip}",
+            "--response-header",
+            "x-served-for: {host}",
+            "--append-response-header",
+            "vary: Cookie",
+        ]);
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("server", "nginx"),
+            ("x-powered-by", "PHP"),
+            ("set-cookie", "session=1"),
+            ("set-cookie", "other=2"),
+            ("cache-control", "max-age=60"),
+            ("vary", "Accept-Encoding"),
+        ] {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+
+        HeaderRules::response(&args).apply(&mut headers, &VARS);
+        assert!(!headers.contains_key("server"));
+        assert!(!headers.contains_key("x-powered-by"));
+        assert_eq!(values(&headers, "set-cookie"), ["other=2", "sid=1"]);
+        assert_eq!(values(&headers, "cache-control"), ["no-store"]);
+        assert_eq!(
+            values(&headers, "x-served-for"),
+            ["192.0.2.1", "example.com"]
+        );
+        assert_eq!(values(&headers, "vary"), ["Accept-Encoding", "Cookie"]);
+
+        // Rules for the other direction don't apply.
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("server"),
+            HeaderValue::from_static("nginx"),
+        );
+        HeaderRules::upstream(&args).apply(&mut headers, &VARS);
+        assert_eq!(values(&headers, "server"), ["nginx"]);
+    }
+
+    #[test]
+    fn test_order() {
+        let args = CliArgs::parse_from([
+            "proxyboi",
+            "http://localhost:3000",
+            "--rewrite-upstream-header",
+            "via ^2 two",
+            "--append-upstream-header",
+            "via: 4",
+        ]);
+        let rules = HeaderRules::upstream(&args);
+        let expected = ["1 first", "two second", "3 third", "4"];
+
+        let mut headers = HeaderMap::new();
+        for value in ["1 first", "2 second", "3 third"] {
+            headers.append(
+                HeaderName::from_static("via"),
+                HeaderValue::from_static(value),
+            );
+        }
+        rules.apply(&mut headers, &VARS);
+        assert_eq!(values_in_order(&headers, "via"), expected);
+
+        let mut headers = http::HeaderMap::new();
+        for value in ["1 first", "2 second", "3 third"] {
+            headers.append("via", HeaderValue::from_static(value));
+            headers.append("x-untouched", HeaderValue::from_static(value));
+        }
+        rules.apply_http(&mut headers, &VARS);
+        assert_eq!(headers.get_all("via").iter().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            headers.get_all("x-untouched").iter().collect::<Vec<_>>(),
+            ["1 first", "2 second", "3 third"]
+        );
+    }
+}
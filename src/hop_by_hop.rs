@@ -0,0 +1,134 @@
+use actix_web::http::{header, HeaderMap, HeaderName, HeaderValue};
+
+/// Headers which only apply to a single connection (RFC 9110 section 7.6.1) or are meant for a
+/// proxy (section 11.7), so they are never passed on.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers which are specific to a connection and not allowed in HTTP/2 (RFC 9113 section 8.2.2)
+/// and HTTP/3 (RFC 9114 section 4.2).
+pub const CONNECTION_SPECIFIC_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Whether `name` is one of the headers which are always hop-by-hop.
+pub fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+}
+
+/// Whether `name` is a connection-specific header, which must not be sent over HTTP/2 or HTTP/3.
+pub fn is_connection_specific(name: &HeaderName) -> bool {
+    CONNECTION_SPECIFIC_HEADERS.contains(&name.as_str())
+}
+
+/// Names of the headers listed in `Connection` header `values`, which are hop-by-hop as well.
+pub fn connection_options<'a>(
+    values: impl IntoIterator<Item = &'a HeaderValue>,
+) -> Vec<HeaderName> {
+    values
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|option| HeaderName::from_bytes(option.trim().to_lowercase().as_bytes()).ok())
+        .collect()
+}
+
+/// Remove hop-by-hop headers from the headers of a request or response to be passed on.
+///
+/// With `keep_upgrade` (for requests whose upgraded connection gets tunnelled to an HTTP/1.1
+/// upstream and `101 Switching Protocols` responses), a protocol upgrade survives as its `Upgrade`
+/// header along with `Connection: upgrade`.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap, keep_upgrade: bool) {
+    let options = connection_options(headers.get_all(header::CONNECTION));
+    let upgrade = if keep_upgrade && options.contains(&header::UPGRADE) {
+        headers.get(header::UPGRADE).cloned()
+    } else {
+        None
+    };
+    for name in options {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+    if let Some(upgrade) = upgrade {
+        headers.insert(header::UPGRADE, upgrade);
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        map
+    }
+
+    fn names(headers: &HeaderMap) -> Vec<String> {
+        let mut names = headers
+            .keys()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut map = headers(&[
+            ("connection", "keep-alive, X-Secret"),
+            ("connection", "x-other"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("te", "trailers"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("x-secret", "1"),
+            ("x-other", "2"),
+            ("accept", "*/*"),
+        ]);
+        remove_hop_by_hop_headers(&mut map, true);
+        assert_eq!(names(&map), ["accept"]);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let upgrade = [
+            ("connection", "Upgrade, x-secret"),
+            ("upgrade", "websocket"),
+            ("x-secret", "1"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ];
+
+        let mut map = headers(&upgrade);
+        remove_hop_by_hop_headers(&mut map, true);
+        assert_eq!(names(&map), ["connection", "sec-websocket-key", "upgrade"]);
+        assert_eq!(map.get("connection").unwrap(), "upgrade");
+        assert_eq!(map.get("upgrade").unwrap(), "websocket");
+
+        let mut map = headers(&upgrade);
+        remove_hop_by_hop_headers(&mut map, false);
+        assert_eq!(names(&map), ["sec-websocket-key"]);
+    }
+}
@@ -0,0 +1,535 @@
+use std::cell::RefCell;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_http::body::{MessageBody, ResponseBody};
+use actix_http::http::{HeaderMap, HeaderName, HeaderValue, Method, Uri, Version};
+use actix_http::{Payload, PayloadStream, Request, Response};
+use actix_service::{IntoServiceFactory, Service, ServiceFactory};
+use actix_web::dev::{AppConfig, ServiceRequest, ServiceResponse};
+use actix_web::web::Bytes;
+use actix_web::App;
+use anyhow::{anyhow, Result};
+use bytes1::{Buf, BufMut, BytesMut};
+use futures::channel::oneshot;
+use futures::{future, pin_mut, stream, StreamExt};
+use log::{debug, error, info, warn};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::rustls as quic_rustls;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio1::sync::{mpsc, Semaphore};
+
+use crate::args::CliArgs;
+use crate::header_order::values_in_order;
+use crate::hop_by_hop::is_connection_specific;
+use crate::server::ListenerInfo;
+
+/// ALPN protocol of HTTP/3 (RFC 9114 section 3.1).
+const H3_ALPN_PROTOCOL: &[u8] = b"h3";
+
+/// Largest request body we read before handing a request to the HTTP service, which is the
+/// default limit of actix-web's `web::Bytes` extractor that the handler takes the body with.
+const MAX_REQUEST_BODY_SIZE: usize = 256 * 1024;
+
+/// How many requests may wait for the HTTP service before we stop reading further ones.
+const REQUEST_QUEUE_SIZE: usize = 64;
+
+/// How many requests the HTTP service handles at once, until their response bodies are sent.
+const MAX_CONCURRENT_REQUESTS: usize = 64;
+
+/// A request received over HTTP/3, on its way to the HTTP service.
+struct Http3Request {
+    head: http1::request::Parts,
+    body: bytes1::Bytes,
+    peer_addr: SocketAddr,
+    respond: oneshot::Sender<Http3Response>,
+}
+
+/// A response of the HTTP service, whose body follows in chunks as the service produces them.
+type Http3Response = http1::Response<mpsc::Receiver<Result<bytes1::Bytes>>>;
+
+/// Build the QUIC configuration presenting `certs` and `key`, as loaded by `tls_utils`.
+fn quic_server_config(
+    certs: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+) -> io::Result<quinn::ServerConfig> {
+    let certs = certs
+        .into_iter()
+        .map(|cert| CertificateDer::from(cert.0))
+        .collect();
+    let key = PrivateKeyDer::try_from(key.0).map_err(io::Error::other)?;
+    let provider = Arc::new(quic_rustls::crypto::ring::default_provider());
+    let mut tls_config = quic_rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&quic_rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    tls_config.alpn_protocols = vec![H3_ALPN_PROTOCOL.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls_config).map_err(io::Error::other)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Serve HTTP/3 on the UDP port of `--listen` using the apps built by `factory`.
+///
+/// quinn needs a newer tokio than actix, so QUIC runs on a separate runtime in its own thread.
+/// Requests are handed over to the HTTP service running on the current actix system, which
+/// means they go through the same handler as requests over HTTP/1.1 and HTTP/2.
+pub fn serve<F, T, B>(
+    args: &CliArgs,
+    certs: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+    factory: F,
+) -> io::Result<()>
+where
+    F: Fn() -> App<T, B> + 'static,
+    T: ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let server_config = quic_server_config(certs, key)?;
+    // Bind here already so that problems with the socket come up on startup.
+    let socket = UdpSocket::bind(args.listen)?;
+    let (sender, receiver) = mpsc::channel(REQUEST_QUEUE_SIZE);
+    actix_rt::spawn(dispatch(factory, receiver));
+    std::thread::Builder::new()
+        .name("proxyboi-http3".to_string())
+        .spawn(move || {
+            let runtime = tokio1::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Couldn't start the HTTP/3 runtime");
+            runtime.block_on(accept(socket, server_config, sender))
+        })?;
+    info!("Starting HTTP/3 service on udp/{}", args.listen);
+    Ok(())
+}
+
+/// Accept QUIC connections on `socket` and serve HTTP/3 on them.
+async fn accept(
+    socket: UdpSocket,
+    server_config: quinn::ServerConfig,
+    requests: mpsc::Sender<Http3Request>,
+) {
+    let endpoint = match quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    ) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Couldn't start HTTP/3 listener: {}", e);
+            return;
+        }
+    };
+    while let Some(incoming) = endpoint.accept().await {
+        let requests = requests.clone();
+        tokio1::spawn(async move {
+            let peer_addr = incoming.remote_address();
+            if let Err(e) = serve_connection(incoming, peer_addr, requests).await {
+                debug!("HTTP/3 connection from {} ended: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    peer_addr: SocketAddr,
+    requests: mpsc::Sender<Http3Request>,
+) -> Result<()> {
+    let connection = h3_quinn::Connection::new(incoming.await?);
+    let mut connection = h3::server::Connection::<_, bytes1::Bytes>::new(connection).await?;
+    while let Some(resolver) = connection.accept().await? {
+        let requests = requests.clone();
+        tokio1::spawn(async move {
+            if let Err(e) = serve_request(resolver, peer_addr, requests).await {
+                warn!("HTTP/3 request from {} failed: {}", peer_addr, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_request(
+    resolver: h3::server::RequestResolver<h3_quinn::Connection, bytes1::Bytes>,
+    peer_addr: SocketAddr,
+    requests: mpsc::Sender<Http3Request>,
+) -> Result<()> {
+    let (request, mut stream) = resolver.resolve_request().await?;
+    let content_length = request
+        .headers()
+        .get(http1::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    let mut too_large = content_length.is_some_and(|length| length > MAX_REQUEST_BODY_SIZE);
+    let mut body = BytesMut::new();
+    while !too_large {
+        match stream.recv_data().await? {
+            Some(chunk) if body.len() + chunk.remaining() <= MAX_REQUEST_BODY_SIZE => {
+                body.put(chunk)
+            }
+            Some(_) => too_large = true,
+            None => break,
+        }
+    }
+    if too_large {
+        warn!(
+            "Refused HTTP/3 request from {} with a body larger than {} bytes",
+            peer_addr, MAX_REQUEST_BODY_SIZE
+        );
+        let mut response = http1::Response::new(());
+        *response.status_mut() = http1::StatusCode::PAYLOAD_TOO_LARGE;
+        stream.send_response(response).await?;
+        // Tell the client to stop sending the rest of the body (RFC 9114 section 4.1).
+        stream.stop_sending(h3::error::Code::H3_NO_ERROR);
+        stream.finish().await?;
+        return Ok(());
+    }
+
+    let (respond, response) = oneshot::channel();
+    let (head, ()) = request.into_parts();
+    requests
+        .send(Http3Request {
+            head,
+            body: body.freeze(),
+            peer_addr,
+            respond,
+        })
+        .await
+        .map_err(|_| anyhow!("HTTP service is gone"))?;
+    let (head, mut body) = response.await?.into_parts();
+
+    stream
+        .send_response(http1::Response::from_parts(head, ()))
+        .await?;
+    while let Some(chunk) = body.recv().await {
+        match chunk {
+            Ok(chunk) => stream.send_data(chunk).await?,
+            Err(e) => {
+                // Finishing the stream would pass off the truncated body as complete.
+                stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                return Err(e);
+            }
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Pass requests received over HTTP/3 to an HTTP service built by `factory`.
+async fn dispatch<F, T, B>(factory: F, mut requests: mpsc::Receiver<Http3Request>)
+where
+    F: Fn() -> App<T, B>,
+    T: ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let app = factory().data(ListenerInfo { secure: true });
+    let service = match app.into_factory().new_service(AppConfig::default()).await {
+        Ok(service) => Rc::new(RefCell::new(service)),
+        Err(()) => {
+            error!("Couldn't start the HTTP service for HTTP/3");
+            return;
+        }
+    };
+    // Requests wait in the queue while all permits are taken, which in turn makes the HTTP/3
+    // connections wait before reading more requests.
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    loop {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        let request = match requests.recv().await {
+            Some(request) => request,
+            None => break,
+        };
+        let service = service.clone();
+        actix_rt::spawn(async move {
+            let response = match into_actix_request(request.head, request.body, request.peer_addr) {
+                Ok(actix_request) => {
+                    let response = service.borrow_mut().call(actix_request);
+                    match response.await {
+                        Ok(response) => into_http3_response(response.into()),
+                        Err(e) => into_http3_response(Response::from_error(e).into_body()),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let (head, body) = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Couldn't handle HTTP/3 request: {}", e);
+                    let mut head = http1::Response::new(());
+                    *head.status_mut() = http1::StatusCode::INTERNAL_SERVER_ERROR;
+                    (head, ResponseBody::Other(actix_http::body::Body::Empty))
+                }
+            };
+            let (chunks, receiver) = mpsc::channel(1);
+            // The client may have gone away in the meantime, which is none of our business.
+            if request.respond.send(head.map(|()| receiver)).is_ok() {
+                send_body(body, chunks).await;
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Turn an HTTP/3 request into a request for the HTTP service.
+fn into_actix_request(
+    head: http1::request::Parts,
+    body: bytes1::Bytes,
+    peer_addr: SocketAddr,
+) -> Result<Request> {
+    let body = Bytes::copy_from_slice(&body);
+    let payload: PayloadStream = Box::pin(stream::once(future::ok(body)));
+    let mut request = Request::with_payload(Payload::Stream(payload));
+    let request_head = request.head_mut();
+    request_head.method = Method::from_bytes(head.method.as_str().as_bytes())?;
+    request_head.uri = head.uri.to_string().parse::<Uri>()?;
+    request_head.version = Version::HTTP_3;
+    request_head.peer_addr = Some(peer_addr);
+    let mut headers = HeaderMap::new();
+    for (name, value) in &head.headers {
+        headers.append(
+            HeaderName::from_bytes(name.as_str().as_bytes())?,
+            HeaderValue::from_bytes(value.as_bytes())?,
+        );
+    }
+    request_head.headers = headers;
+    Ok(request)
+}
+
+/// Turn a response of the HTTP service into the head of an HTTP/3 response and its body.
+fn into_http3_response<B: MessageBody>(
+    mut response: Response<B>,
+) -> Result<(http1::Response<()>, ResponseBody<B>)> {
+    let mut builder = http1::Response::builder().status(response.status().as_u16());
+    for name in response
+        .headers()
+        .keys()
+        .filter(|name| !is_connection_specific(name))
+    {
+        for value in values_in_order(response.headers(), name.as_str()) {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+    }
+    let head = builder.body(())?;
+    Ok((head, response.take_body()))
+}
+
+/// Pass the chunks of `body` on to `chunks` until the body ends or the client goes away.
+async fn send_body<B: MessageBody>(
+    body: ResponseBody<B>,
+    chunks: mpsc::Sender<Result<bytes1::Bytes>>,
+) {
+    pin_mut!(body);
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk
+            .map(|chunk| bytes1::Bytes::copy_from_slice(&chunk))
+            .map_err(|e| anyhow!("Couldn't read response body: {}", e));
+        let failed = chunk.is_err();
+        if chunks.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hop_by_hop::CONNECTION_SPECIFIC_HEADERS;
+    use actix_web::{web, HttpResponse};
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_into_actix_request() {
+        let (head, ()) = http1::Request::builder()
+            .method("PATCH")
+            .uri("https://example.com/path?query")
+            .header("x-hop", "1")
+            .header("x-hop", "2")
+            .header("x-hop", "3")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let peer_addr = "[::1]:1234".parse().unwrap();
+        let mut request =
+            into_actix_request(head, bytes1::Bytes::from_static(b"body"), peer_addr).unwrap();
+        assert_eq!(request.method(), Method::PATCH);
+        assert_eq!(request.uri(), "https://example.com/path?query");
+        assert_eq!(request.version(), Version::HTTP_3);
+        assert_eq!(request.head().peer_addr, Some(peer_addr));
+        assert_eq!(
+            values_in_order(&request.head().headers, "x-hop"),
+            ["1", "2", "3"]
+        );
+        let body = request.take_payload().next().await.unwrap().unwrap();
+        assert_eq!(body, "body");
+    }
+
+    #[actix_rt::test]
+    async fn test_into_http3_response() {
+        let mut response = Response::Created().body("created");
+        let headers = response.headers_mut();
+        for cookie in ["a=1", "b=2", "c=3"] {
+            headers.append(
+                HeaderName::from_static("set-cookie"),
+                HeaderValue::from_static(cookie),
+            );
+        }
+        for name in CONNECTION_SPECIFIC_HEADERS {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static("close"),
+            );
+        }
+
+        let (head, body) = into_http3_response(response).unwrap();
+        assert_eq!(head.status(), http1::StatusCode::CREATED);
+        assert_eq!(
+            head.headers()
+                .get_all(http1::header::SET_COOKIE)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["a=1", "b=2", "c=3"]
+        );
+        for name in CONNECTION_SPECIFIC_HEADERS {
+            assert!(!head.headers().contains_key(*name), "{}", name);
+        }
+        pin_mut!(body);
+        assert_eq!(body.next().await.unwrap().unwrap(), "created");
+        assert!(body.next().await.is_none());
+    }
+
+    /// Send requests over HTTP/3 to `addr`, which presents `cert`, and check the responses.
+    async fn run_client(
+        addr: SocketAddr,
+        cert: CertificateDer<'static>,
+        release: oneshot::Sender<()>,
+    ) {
+        let mut roots = quic_rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let provider = Arc::new(quic_rustls::crypto::ring::default_provider());
+        let mut tls_config = quic_rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&quic_rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls_config.alpn_protocols = vec![H3_ALPN_PROTOCOL.to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls_config).unwrap();
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        let connection = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+        let (mut driver, mut requests) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        tokio1::spawn(async move {
+            let _endpoint = endpoint;
+            driver.wait_idle().await
+        });
+
+        // The first chunk of the response arrives before the service produces the second one.
+        let request = http1::Request::post("https://localhost/stream")
+            .body(())
+            .unwrap();
+        let mut stream = requests.send_request(request).await.unwrap();
+        stream
+            .send_data(bytes1::Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        stream.finish().await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), http1::StatusCode::OK);
+        let mut chunk = stream.recv_data().await.unwrap().unwrap();
+        assert_eq!(chunk.copy_to_bytes(chunk.remaining()), "hello");
+        release.send(()).unwrap();
+        let mut chunk = stream.recv_data().await.unwrap().unwrap();
+        assert_eq!(chunk.copy_to_bytes(chunk.remaining()), "bye");
+        assert!(stream.recv_data().await.unwrap().is_none());
+
+        let request = http1::Request::post("https://localhost/stream")
+            .header(http1::header::CONTENT_LENGTH, MAX_REQUEST_BODY_SIZE + 1)
+            .body(())
+            .unwrap();
+        let mut stream = requests.send_request(request).await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), http1::StatusCode::PAYLOAD_TOO_LARGE);
+
+        let request = http1::Request::post("https://localhost/stream")
+            .body(())
+            .unwrap();
+        let mut stream = requests.send_request(request).await.unwrap();
+        let body = bytes1::Bytes::from(vec![0; MAX_REQUEST_BODY_SIZE + 1]);
+        // The server stops reading the body, which may already fail sending it.
+        let _ = stream.send_data(body).await;
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), http1::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = cert.serialize_der().unwrap();
+        let server_config = quic_server_config(
+            vec![rustls::Certificate(cert_der.clone())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let (release, released) = oneshot::channel::<()>();
+        let released = Rc::new(RefCell::new(Some(released)));
+        let factory = move || {
+            let released = released.clone();
+            App::new().route(
+                "/stream",
+                web::post().to(move |body: Bytes| {
+                    let released = released.borrow_mut().take().unwrap();
+                    let bye = async move {
+                        let _ = released.await;
+                        Ok::<_, actix_web::Error>(Bytes::from_static(b"bye"))
+                    };
+                    HttpResponse::Ok().streaming(
+                        stream::once(future::ok(body)).chain(stream::once(Box::pin(bye))),
+                    )
+                }),
+            )
+        };
+        let (sender, receiver) = mpsc::channel(REQUEST_QUEUE_SIZE);
+        actix_rt::spawn(dispatch(factory, receiver));
+
+        let (done, finished) = oneshot::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio1::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                tokio1::spawn(accept(socket, server_config, sender));
+                let client = run_client(addr, CertificateDer::from(cert_der), release);
+                tokio1::time::timeout(Duration::from_secs(10), client)
+                    .await
+                    .unwrap();
+            });
+            done.send(()).unwrap();
+        });
+        finished.await.expect("HTTP/3 client failed");
+    }
+}
@@ -0,0 +1,355 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+
+#[cfg(unix)]
+use log::info;
+
+use crate::args::CliArgs;
+
+/// A socket to accept connections on.
+#[derive(Debug)]
+pub enum Listener {
+    /// Bind a new TCP socket to this address
+    Addr(SocketAddr),
+
+    /// An already bound TCP socket (eg. passed by systemd)
+    Tcp(TcpListener),
+
+    /// An already bound Unix domain socket
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Addr(addr) => write!(f, "{}", addr),
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "unknown"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+            {
+                Some(path) => write!(f, "unix:{}", path),
+                None => write!(f, "unix:unknown"),
+            },
+        }
+    }
+}
+
+/// Figure out which sockets to listen on.
+///
+/// Sockets passed by systemd take precedence over `--listen-unix` which in turn replaces
+/// `--listen`.
+pub fn listeners(args: &CliArgs) -> io::Result<Vec<Listener>> {
+    #[cfg(unix)]
+    {
+        let listeners = systemd::listeners()?;
+        if !listeners.is_empty() {
+            info!("Using {} socket(s) passed by systemd", listeners.len());
+            return Ok(listeners);
+        }
+
+        if let Some(path) = &args.listen_unix {
+            return Ok(vec![Listener::Unix(bind_unix(
+                path,
+                args.listen_unix_mode,
+                args.listen_unix_owner.as_deref(),
+            )?)]);
+        }
+    }
+
+    #[cfg(not(unix))]
+    if args.listen_unix.is_some() {
+        return Err(io::Error::other(
+            "Unix domain sockets are not supported on this platform",
+        ));
+    }
+
+    Ok(vec![Listener::Addr(args.listen)])
+}
+
+/// Bind a Unix domain socket at `path` and apply the given `mode` and `owner` ("user[:group]").
+///
+/// A stale socket left over from a previous run is removed first, any other file is left alone.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>, owner: Option<&str>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Can't bind Unix socket {}: {}", path.display(), e),
+        )
+    })?;
+
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if let Some(owner) = owner {
+        let (user, group) = match owner.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (owner, None),
+        };
+        let uid = if user.is_empty() {
+            None
+        } else {
+            Some(lookup_user(user)?)
+        };
+        let gid = group.map(lookup_group).transpose()?;
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+
+    Ok(listener)
+}
+
+/// Resolve a user name (or numeric ID) to a user ID.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> io::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).map_err(io::Error::other)?;
+    // Safety: `getpwnam` is only called during startup, before any other threads could call
+    // it concurrently.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::other(format!("Unknown user {}", user)));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// Resolve a group name (or numeric ID) to a group ID.
+#[cfg(unix)]
+fn lookup_group(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(io::Error::other)?;
+    // Safety: see `lookup_user`.
+    let group_entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if group_entry.is_null() {
+        return Err(io::Error::other(format!("Unknown group {}", group)));
+    }
+    Ok(unsafe { (*group_entry).gr_gid })
+}
+
+#[cfg(unix)]
+mod systemd {
+    use std::env;
+    use std::io;
+    use std::net::TcpListener;
+    use std::ops::Range;
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::os::unix::net::UnixListener;
+
+    use super::Listener;
+
+    /// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`).
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// Take over the sockets passed by systemd socket activation, if any.
+    ///
+    /// Follows the protocol described in sd_listen_fds(3): the sockets are only meant for us if
+    /// `LISTEN_PID` matches our PID. The environment variables are removed afterwards so they
+    /// aren't inherited by any child process.
+    pub fn listeners() -> io::Result<Vec<Listener>> {
+        let listen_pid = env::var("LISTEN_PID").ok();
+        let listen_fds = env::var("LISTEN_FDS").ok();
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        let fds = passed_fds(
+            listen_pid.as_deref(),
+            listen_fds.as_deref(),
+            std::process::id(),
+        )?;
+
+        fds.map(|fd| {
+            // Safety: systemd hands these file descriptors over to us and nothing else in
+            // this process knows about them.
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                match socket_family(fd)? {
+                    libc::AF_UNIX => {
+                        let listener = UnixListener::from_raw_fd(fd);
+                        listener.set_nonblocking(true)?;
+                        Ok(Listener::Unix(listener))
+                    }
+                    libc::AF_INET | libc::AF_INET6 => {
+                        let listener = TcpListener::from_raw_fd(fd);
+                        listener.set_nonblocking(true)?;
+                        Ok(Listener::Tcp(listener))
+                    }
+                    family => Err(io::Error::other(format!(
+                        "Socket {} passed by systemd has unsupported address family {}",
+                        fd, family
+                    ))),
+                }
+            }
+        })
+        .collect()
+    }
+
+    /// The file descriptors passed to the process `pid` according to the values of `LISTEN_PID`
+    /// and `LISTEN_FDS`, which are none if they're meant for another process.
+    pub(super) fn passed_fds(
+        listen_pid: Option<&str>,
+        listen_fds: Option<&str>,
+        pid: u32,
+    ) -> io::Result<Range<RawFd>> {
+        let for_us = listen_pid
+            .and_then(|listen_pid| listen_pid.parse::<u32>().ok())
+            .is_some_and(|listen_pid| listen_pid == pid);
+        let fds = match listen_fds {
+            Some(fds) if for_us => fds
+                .parse::<RawFd>()
+                .ok()
+                .filter(|fds| *fds >= 0)
+                .ok_or_else(|| io::Error::other(format!("Invalid LISTEN_FDS: {}", fds)))?,
+            _ => 0,
+        };
+        Ok(LISTEN_FDS_START..LISTEN_FDS_START + fds)
+    }
+
+    /// Get the address family of the socket `fd`.
+    fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        // Safety: `addr` is large enough for any socket address and `len` tells so.
+        let result =
+            unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(addr.ss_family as libc::c_int)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::path::PathBuf;
+
+    /// A new directory for a test, which is removed along with its contents when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("proxyboi-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_passed_fds() {
+        assert_eq!(
+            systemd::passed_fds(Some("42"), Some("2"), 42).unwrap(),
+            3..5
+        );
+        assert!(systemd::passed_fds(Some("42"), Some("0"), 42)
+            .unwrap()
+            .is_empty());
+
+        // The sockets are meant for another process, which we might have been forked from.
+        assert!(systemd::passed_fds(Some("41"), Some("2"), 42)
+            .unwrap()
+            .is_empty());
+        assert!(systemd::passed_fds(None, Some("2"), 42).unwrap().is_empty());
+        assert!(systemd::passed_fds(Some("x"), Some("2"), 42)
+            .unwrap()
+            .is_empty());
+        assert!(systemd::passed_fds(Some("42"), None, 42)
+            .unwrap()
+            .is_empty());
+
+        assert!(systemd::passed_fds(Some("42"), Some("two"), 42).is_err());
+        assert!(systemd::passed_fds(Some("42"), Some("-1"), 42).is_err());
+    }
+
+    fn listen_unix_mode(mode: &str) -> Result<Option<u32>, clap::Error> {
+        let args = CliArgs::try_parse_from([
+            "proxyboi",
+            "--listen-unix",
+            "/run/proxyboi.sock",
+            "--listen-unix-mode",
+            mode,
+            "http://localhost:3000",
+        ])?;
+        Ok(args.listen_unix_mode)
+    }
+
+    #[test]
+    fn test_listen_unix_mode() {
+        assert_eq!(listen_unix_mode("660").unwrap(), Some(0o660));
+        assert_eq!(listen_unix_mode("0660").unwrap(), Some(0o660));
+        assert_eq!(listen_unix_mode("1777").unwrap(), Some(0o1777));
+        assert!(listen_unix_mode("668").is_err());
+        assert!(listen_unix_mode("17777").is_err());
+        assert!(listen_unix_mode("rw").is_err());
+        assert!(CliArgs::try_parse_from([
+            "proxyboi",
+            "--listen-unix-mode",
+            "660",
+            "http://localhost:3000"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = TempDir::new("bind-unix");
+        let path = dir.0.join("proxyboi.sock");
+        let listener = bind_unix(&path, Some(0o600), None).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(
+            Listener::Unix(listener).to_string(),
+            format!("unix:{}", path.display())
+        );
+
+        // The socket is left behind once the listener is gone, which doesn't keep us from
+        // binding it again.
+        assert!(path.exists());
+        bind_unix(&path, None, None).unwrap();
+    }
+
+    #[test]
+    fn test_bind_unix_keeps_other_files() {
+        let dir = TempDir::new("bind-unix-file");
+        let path = dir.0.join("proxyboi.sock");
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(bind_unix(&path, None, None).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+    }
+}
@@ -1,11 +1,12 @@
+use std::time::Duration;
+
 use actix_web::client::{ClientRequest, ClientResponse};
 use actix_web::{HttpRequest, HttpResponse};
 use chrono::prelude::*;
 use inflector::Inflector;
 use yansi::Paint;
 
-pub fn log_incoming_request(req: &HttpRequest, verbose: bool) -> String {
-    let conn_info = req.connection_info();
+pub fn log_incoming_request(req: &HttpRequest, remote: &str, verbose: bool) -> String {
     let local_time = Local::now();
     let time = local_time.format("[%d/%b/%Y:%H:%M:%S %z]").to_string();
 
@@ -51,9 +52,8 @@ pub fn log_incoming_request(req: &HttpRequest, verbose: bool) -> String {
         );
         format!(
             "Connection from {remote} at {time}\n{req_banner} from {remote_pretty}\n{req_info}",
-            remote = conn_info.realip_remote_addr().unwrap_or("unknown"),
-            remote_pretty =
-                Paint::magenta(conn_info.realip_remote_addr().unwrap_or("unknown")).bold(),
+            remote = remote,
+            remote_pretty = Paint::magenta(remote).bold(),
             time = time,
             req_banner = Paint::green("┌─Incoming request").bold(),
             req_info = req_info,
@@ -61,7 +61,7 @@ pub fn log_incoming_request(req: &HttpRequest, verbose: bool) -> String {
     } else {
         format!(
             "Connection from {remote} at {time}",
-            remote = conn_info.realip_remote_addr().unwrap_or("unknown"),
+            remote = remote,
             time = time
         )
     }
@@ -198,3 +198,188 @@ pub fn log_outgoing_response(resp: &HttpResponse, remote: &str, verbose: bool) -
         String::new()
     }
 }
+
+/// Name of a gRPC status code as defined in
+/// https://github.com/grpc/grpc/blob/master/doc/statuscodes.md
+fn grpc_status_name(code: &str) -> &'static str {
+    match code {
+        "0" => "OK",
+        "1" => "CANCELLED",
+        "2" => "UNKNOWN",
+        "3" => "INVALID_ARGUMENT",
+        "4" => "DEADLINE_EXCEEDED",
+        "5" => "NOT_FOUND",
+        "6" => "ALREADY_EXISTS",
+        "7" => "PERMISSION_DENIED",
+        "8" => "RESOURCE_EXHAUSTED",
+        "9" => "FAILED_PRECONDITION",
+        "10" => "ABORTED",
+        "11" => "OUT_OF_RANGE",
+        "12" => "UNIMPLEMENTED",
+        "13" => "INTERNAL",
+        "14" => "UNAVAILABLE",
+        "15" => "DATA_LOSS",
+        "16" => "UNAUTHENTICATED",
+        _ => "",
+    }
+}
+
+pub fn log_incoming_grpc_call(remote: &str, service: &str, method: &str, verbose: bool) -> String {
+    let local_time = Local::now();
+    let time = local_time.format("[%d/%b/%Y:%H:%M:%S %z]").to_string();
+
+    if verbose {
+        format!(
+            "Connection from {remote} at {time}\n{req_banner} from {remote_pretty}\n{deco} {service}/{method}",
+            remote = remote,
+            time = time,
+            req_banner = Paint::green("┌─Incoming gRPC call").bold(),
+            remote_pretty = Paint::magenta(remote).bold(),
+            deco = Paint::green("│").bold(),
+            service = service.cyan(),
+            method = Paint::green(method),
+        )
+    } else {
+        format!(
+            "Connection from {remote} at {time}",
+            remote = remote,
+            time = time
+        )
+    }
+}
+
+pub fn log_upstream_grpc_call(uri: &str, service: &str, method: &str, verbose: bool) -> String {
+    if verbose {
+        format!(
+            "{req_banner} to {uri}\n{deco} {service}/{method}",
+            req_banner = "┌─Upstream gRPC call".bold().cyan(),
+            uri = uri.yellow(),
+            deco = "│".bold().cyan(),
+            service = service.cyan(),
+            method = Paint::green(method),
+        )
+    } else {
+        String::new()
+    }
+}
+
+pub fn log_grpc_status(
+    remote: &str,
+    status: Option<&str>,
+    message: Option<&str>,
+    verbose: bool,
+) -> String {
+    if verbose {
+        let status_line = match status {
+            Some(status) => format!(
+                "{grpc} {status_code} {status_text}",
+                grpc = "gRPC".blue(),
+                status_code = status.blue(),
+                status_text = grpc_status_name(status).cyan(),
+            ),
+            None => format!(
+                "{grpc} {missing}",
+                grpc = "gRPC".blue(),
+                missing = "no status".cyan()
+            ),
+        };
+        let message_line = message
+            .map(|message| {
+                format!(
+                    "\n{deco} {message}",
+                    deco = Paint::red("│").bold(),
+                    message = message
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            "{resp_banner} to {remote}\n{deco} {status_line}{message_line}",
+            resp_banner = Paint::red("┌─Outgoing gRPC status").bold(),
+            remote = Paint::magenta(remote).bold(),
+            deco = Paint::red("│").bold(),
+            status_line = status_line,
+            message_line = message_line,
+        )
+    } else {
+        String::new()
+    }
+}
+
+pub fn log_tcp_connection_opened(
+    remote: &str,
+    upstream_uri: &str,
+    server_name: Option<&str>,
+    verbose: bool,
+) -> String {
+    let local_time = Local::now();
+    let time = local_time.format("[%d/%b/%Y:%H:%M:%S %z]").to_string();
+
+    if verbose {
+        format!(
+            "Connection from {remote} at {time}\n{conn_banner} from {remote_pretty} to {uri}{server_name_line}",
+            remote = remote,
+            time = time,
+            conn_banner = Paint::green("┌─Opened TCP connection").bold(),
+            remote_pretty = Paint::magenta(remote).bold(),
+            uri = upstream_uri.yellow(),
+            server_name_line = server_name
+                .map(|server_name| format!(
+                    "\n{deco} {sni} {server_name}",
+                    deco = Paint::green("│").bold(),
+                    sni = "SNI".blue(),
+                    server_name = server_name.cyan()
+                ))
+                .unwrap_or_default(),
+        )
+    } else {
+        format!(
+            "Connection from {remote} to {uri} at {time}",
+            remote = remote,
+            uri = upstream_uri,
+            time = time
+        )
+    }
+}
+
+pub fn log_tcp_connection_closed(
+    remote: &str,
+    upstream_uri: &str,
+    sent: u64,
+    received: u64,
+    duration: Duration,
+    error: Option<&std::io::Error>,
+    verbose: bool,
+) -> String {
+    if verbose {
+        let error_line = error
+            .map(|error| {
+                format!(
+                    "\n{deco} {error}",
+                    deco = Paint::red("│").bold(),
+                    error = error
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            "{conn_banner} from {remote} to {uri}\n{deco} {sent} bytes sent, {received} bytes received in {duration:.3}s{error_line}",
+            conn_banner = Paint::red("┌─Closed TCP connection").bold(),
+            remote = Paint::magenta(remote).bold(),
+            uri = upstream_uri.yellow(),
+            deco = Paint::red("│").bold(),
+            sent = sent.blue(),
+            received = received.blue(),
+            duration = duration.as_secs_f64(),
+            error_line = error_line,
+        )
+    } else {
+        format!(
+            "Connection from {remote} to {uri} closed ({sent} bytes sent, {received} bytes received in {duration:.3}s){error}",
+            remote = remote,
+            uri = upstream_uri,
+            sent = sent,
+            received = received,
+            duration = duration.as_secs_f64(),
+            error = error.map(|error| format!(": {}", error)).unwrap_or_default(),
+        )
+    }
+}
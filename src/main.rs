@@ -1,39 +1,51 @@
+mod acme;
 mod args;
+mod body_filter;
+mod cidr;
+mod client;
+mod client_ip;
 mod error;
+mod forward_proxy;
 mod forwarded_header;
+mod grpc;
+mod h2c;
 mod handler;
+mod header_order;
+mod header_rules;
+mod hop_by_hop;
+mod http3;
+mod listener;
 mod logging;
+mod outbound;
+mod proxy_protocol;
+mod response_headers;
+mod rewind;
+mod rewrite;
+mod server;
+mod sni;
+mod socks5;
+mod tcp;
 mod tls_utils;
+mod unix;
+mod upstream_path;
 
 use std::sync::Arc;
-use std::time::Duration;
 
-use actix_web::client::{ClientBuilder, Connector};
-use actix_web::{web, App, HttpServer};
+use actix_server::Server;
+use actix_web::{web, App};
 use clap::Parser;
-use log::trace;
-use rustls::{
-    Certificate, ClientConfig, NoClientAuth, RootCertStore, ServerCertVerified, ServerCertVerifier,
-    ServerConfig, TLSError,
-};
 
-use crate::tls_utils::load_cert;
-use crate::tls_utils::load_private_key;
-
-struct NoVerifier;
-
-impl ServerCertVerifier for NoVerifier {
-    fn verify_server_cert(
-        &self,
-        _roots: &RootCertStore,
-        _presented_certs: &[Certificate],
-        dns_name: webpki::DNSNameRef,
-        _ocsp_response: &[u8],
-    ) -> Result<ServerCertVerified, TLSError> {
-        trace!("decoding dns: {:#?}", dns_name);
-        Ok(ServerCertVerified::assertion())
-    }
-}
+use crate::acme::AcmeState;
+use crate::args::AcmeChallenge;
+use crate::client::{client_config, forward_proxy_client, upstream_client};
+use crate::forward_proxy::ForwardProxy;
+use crate::grpc::GrpcProxy;
+use crate::listener::Listener;
+use crate::server::{ListenerOptions, ACME_TLS_ALPN_PROTOCOL};
+use crate::tls_utils::{
+    certificate_expiry, check_expiry, load_cert, load_private_key, monitor_expiry, server_config,
+    validate_chain,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -42,7 +54,7 @@ async fn main() -> std::io::Result<()> {
     #[cfg(windows)]
     Paint::enable_windows_ascii();
 
-    let args = args::CliArgs::parse();
+    let mut args = args::CliArgs::parse();
 
     let log_level = if args.quiet {
         simplelog::LevelFilter::Error
@@ -62,40 +74,135 @@ async fn main() -> std::io::Result<()> {
             .expect("Couldn't initialize logger")
     }
 
+    if args.outbound_proxy_from_env {
+        outbound::apply_env(&mut args).map_err(std::io::Error::other)?;
+    }
+
     let args_ = args.clone();
-    let mut http_server = HttpServer::new(move || {
-        let connector = if args.insecure {
-            let mut client_config = ClientConfig::new();
-            client_config
-                .dangerous()
-                .set_certificate_verifier(Arc::new(NoVerifier {}));
-            Connector::new()
-                .rustls(Arc::new(client_config))
-                .timeout(Duration::from_secs(args.timeout))
-                .finish()
-        } else {
-            Connector::new()
-                .timeout(Duration::from_secs(args.timeout))
-                .finish()
-        };
-        let client = ClientBuilder::new().connector(connector).finish();
+    let tls_config = Arc::new(client_config(&args));
+    let app = move || {
+        let client = upstream_client(&args, tls_config.clone(), None);
+        let forward_proxy_client = forward_proxy_client(&args, tls_config.clone());
 
         App::new()
             .data(client)
+            .data(forward_proxy_client)
+            .app_data(web::Data::from(tls_config.clone()))
             .data(args.clone())
             .default_service(web::route().to(handler::forward))
-    });
+    };
 
-    if let (Some(tls_cert), Some(tls_key)) = (args_.tls_cert, args_.tls_key) {
-        let cert_file = load_cert(&tls_cert)?;
-        let key_file = load_private_key(&tls_key)?;
-        let mut rustls_config = ServerConfig::new(NoClientAuth::new());
+    let options = ListenerOptions {
+        grpc: if args_.grpc {
+            Some(GrpcProxy::new(args_.clone(), client_config(&args_)))
+        } else {
+            None
+        },
+        proxy_protocol: args_.proxy_protocol,
+        forward_proxy: if args_.forward_proxy {
+            Some(ForwardProxy::new(args_.clone()))
+        } else {
+            None
+        },
+    };
+
+    match &args_.upstream {
+        Some(upstream) if tcp::is_tcp_upstream(&args_) => {
+            tcp::upstream_addr(upstream)?;
+            if args_.forward_proxy {
+                return Err(std::io::Error::other(
+                    "--forward-proxy can't be combined with a tcp:// upstream",
+                ));
+            }
+            if args_.http3 {
+                return Err(std::io::Error::other(
+                    "--http3 can't be combined with a tcp:// upstream",
+                ));
+            }
+        }
+        _ if !args_.sni_routes.is_empty() => {
+            return Err(std::io::Error::other(
+                "--sni-route requires a tcp:// upstream to fall back to",
+            ));
+        }
+        _ => {}
+    }
+
+    let listeners = listener::listeners(&args_)?;
+    let mut http_server = Server::build();
+    let mut server_tls_config = None;
+    if !args_.acme_domains.is_empty() {
+        let acme_state = Arc::new(AcmeState::default());
+        let mut rustls_config = server_config(&args_)?;
+        rustls_config.cert_resolver = acme_state.clone();
+        if args_.acme_challenge == AcmeChallenge::TlsAlpn01 {
+            rustls_config
+                .alpn_protocols
+                .push(ACME_TLS_ALPN_PROTOCOL.to_vec());
+        }
+        server_tls_config = Some(Arc::new(rustls_config));
+
+        if args_.acme_challenge == AcmeChallenge::Http01 {
+            let args = args_.clone();
+            let acme_state = acme_state.clone();
+            http_server = server::bind_http(
+                http_server,
+                Listener::Addr(args_.acme_http_listen),
+                ListenerOptions::default(),
+                move || {
+                    App::new()
+                        .data(args.clone())
+                        .app_data(web::Data::from(acme_state.clone()))
+                        .default_service(web::route().to(acme::http_challenge))
+                },
+            )?;
+        }
+
+        actix_rt::spawn(acme::run(args_.clone(), acme_state));
+    } else if let (Some(tls_cert), Some(tls_key)) = (&args_.tls_cert, &args_.tls_key) {
+        let cert_file = load_cert(tls_cert)?;
+        validate_chain(&cert_file)?;
+        let expiry = certificate_expiry(&cert_file[0])?;
+        check_expiry(
+            tls_cert,
+            expiry,
+            args_.cert_expiry_warning_days,
+            args_.allow_expired_cert,
+        )?;
+        actix_rt::spawn(monitor_expiry(
+            tls_cert.clone(),
+            expiry,
+            args_.cert_expiry_warning_days,
+        ));
+        let key_file = load_private_key(tls_key)?;
+        if args_.http3 {
+            http3::serve(&args_, cert_file.clone(), key_file.clone(), app.clone())?;
+        }
+        let mut rustls_config = server_config(&args_)?;
         rustls_config
             .set_single_cert(cert_file, key_file)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
-        http_server = http_server.bind_rustls(args_.listen, rustls_config)?;
-    } else {
-        http_server = http_server.bind(args_.listen)?;
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        server_tls_config = Some(Arc::new(rustls_config));
+    }
+
+    for listener in listeners {
+        http_server = match &server_tls_config {
+            _ if args_.socks5 => socks5::bind_socks5(http_server, listener, args_.clone())?,
+            _ if tcp::is_tcp_upstream(&args_) => tcp::bind_tcp(
+                http_server,
+                listener,
+                args_.clone(),
+                server_tls_config.clone(),
+            )?,
+            Some(tls_config) => server::bind_https(
+                http_server,
+                listener,
+                tls_config.clone(),
+                options.clone(),
+                app.clone(),
+            )?,
+            None => server::bind_http(http_server, listener, options.clone(), app.clone())?,
+        };
     }
     http_server.run().await
 }
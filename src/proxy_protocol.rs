@@ -0,0 +1,258 @@
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Start of a version 1 (text) header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// Maximum length of a version 1 header including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Signature starting a version 2 (binary) header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Addresses of a connection as seen by the proxy in front of us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid PROXY protocol header: {}", message),
+    )
+}
+
+/// Read a PROXY protocol header (version 1 or 2) from the start of `io`.
+///
+/// Exactly the header is consumed, everything after it is left in `io`. Returns `None` for
+/// headers which don't carry any addresses (eg. health checks by the proxy itself).
+///
+/// See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+pub async fn read_header<T>(io: &mut T) -> io::Result<Option<ProxiedAddrs>>
+where
+    T: AsyncRead + Unpin,
+{
+    // Both versions have at least this many bytes.
+    let mut header = vec![0; V2_SIGNATURE.len()];
+    io.read_exact(&mut header).await?;
+
+    if header == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        io.read_exact(&mut fixed).await?;
+        let mut payload = vec![0; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        io.read_exact(&mut payload).await?;
+        parse_v2(fixed[0], fixed[1], &payload)
+    } else if header.starts_with(V1_PREFIX) {
+        // The header has no length field so we have to read it byte by byte to not consume
+        // anything beyond it.
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= V1_MAX_LENGTH {
+                return Err(invalid("too long"));
+            }
+            header.push(io.read_u8().await?);
+        }
+        let line =
+            std::str::from_utf8(&header[..header.len() - 2]).map_err(|_| invalid("not UTF-8"))?;
+        parse_v1(line)
+    } else {
+        Err(invalid("missing"))
+    }
+}
+
+/// Parse a version 1 header like "PROXY TCP4 192.0.2.1 192.0.2.2 56324 443".
+fn parse_v1(line: &str) -> io::Result<Option<ProxiedAddrs>> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {}
+        _ => return Err(invalid(line)),
+    }
+    let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(
+            ip.parse::<IpAddr>().map_err(|_| invalid(line))?,
+            port.parse::<u16>().map_err(|_| invalid(line))?,
+        ))
+    };
+    Ok(Some(ProxiedAddrs {
+        source: addr(fields[2], fields[4])?,
+        destination: addr(fields[3], fields[5])?,
+    }))
+}
+
+/// Parse the part of a version 2 header following the signature.
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<Option<ProxiedAddrs>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    match family >> 4 {
+        // AF_INET
+        1 if payload.len() >= 12 => {
+            let source: [u8; 4] = payload[0..4]
+                .try_into()
+                .expect("Slice has the right length");
+            let destination: [u8; 4] = payload[4..8]
+                .try_into()
+                .expect("Slice has the right length");
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(source.into(), port(&payload[8..10])),
+                destination: SocketAddr::new(destination.into(), port(&payload[10..12])),
+            }))
+        }
+        // AF_INET6
+        2 if payload.len() >= 36 => {
+            let source: [u8; 16] = payload[0..16]
+                .try_into()
+                .expect("Slice has the right length");
+            let destination: [u8; 16] = payload[16..32]
+                .try_into()
+                .expect("Slice has the right length");
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(source.into(), port(&payload[32..34])),
+                destination: SocketAddr::new(destination.into(), port(&payload[34..36])),
+            }))
+        }
+        1 | 2 => Err(invalid("address block too short")),
+        // AF_UNSPEC or AF_UNIX, neither of which gives us anything useful
+        _ => Ok(None),
+    }
+}
+
+/// Build a version 2 header for a connection from `addrs`.
+///
+/// Without addresses, a LOCAL header is built which tells the receiver to use the real
+/// connection addresses.
+pub fn v2_header(addrs: Option<ProxiedAddrs>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    match addrs {
+        None => header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]),
+        Some(ProxiedAddrs {
+            source,
+            destination,
+        }) => match (source.ip(), destination.ip()) {
+            (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                // PROXY command, TCP over IPv4
+                header.extend_from_slice(&[0x21, 0x11, 0x00, 12]);
+                header.extend_from_slice(&source_ip.octets());
+                header.extend_from_slice(&destination_ip.octets());
+                header.extend_from_slice(&source.port().to_be_bytes());
+                header.extend_from_slice(&destination.port().to_be_bytes());
+            }
+            (source_ip, destination_ip) => {
+                // Both addresses have to be of the same family so mixed ones are sent as IPv6.
+                let to_v6 = |ip: IpAddr| match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                // PROXY command, TCP over IPv6
+                header.extend_from_slice(&[0x21, 0x21, 0x00, 36]);
+                header.extend_from_slice(&to_v6(source_ip).octets());
+                header.extend_from_slice(&to_v6(destination_ip).octets());
+                header.extend_from_slice(&source.port().to_be_bytes());
+                header.extend_from_slice(&destination.port().to_be_bytes());
+            }
+        },
+    }
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    async fn read(mut input: &[u8]) -> (io::Result<Option<ProxiedAddrs>>, Vec<u8>) {
+        let result = read_header(&mut input).await;
+        (result, input.to_vec())
+    }
+
+    fn addrs(source: &str, destination: &str) -> ProxiedAddrs {
+        ProxiedAddrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_v1_tcp4() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /").await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addrs("192.0.2.1:56324", "192.0.2.2:443"))
+        );
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[actix_rt::test]
+    async fn test_v1_tcp6() {
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 80\r\n").await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addrs("[2001:db8::1]:4711", "[2001:db8::2]:80"))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_v1_unknown() {
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nGET /").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[actix_rt::test]
+    async fn test_v2_roundtrip() {
+        for expected in [
+            addrs("192.0.2.1:56324", "192.0.2.2:443"),
+            addrs("[2001:db8::1]:4711", "[2001:db8::2]:80"),
+        ] {
+            let mut input = v2_header(Some(expected));
+            input.extend_from_slice(b"GET /");
+            let (result, rest) = read(&input).await;
+            assert_eq!(result.unwrap(), Some(expected));
+            assert_eq!(rest, b"GET /");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_v2_mixed_families() {
+        let (result, _) = read(&v2_header(Some(addrs("192.0.2.1:1", "[2001:db8::2]:2")))).await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addrs("[::ffff:192.0.2.1]:1", "[2001:db8::2]:2"))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_v2_local() {
+        let (result, rest) = read(&v2_header(None)).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"");
+    }
+
+    #[actix_rt::test]
+    async fn test_missing_header() {
+        let (result, _) = read(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(result.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_v1_too_long() {
+        let mut input = b"PROXY TCP4 ".to_vec();
+        input.extend_from_slice(&[b'1'; 200]);
+        let (result, _) = read(&input).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::net::IpAddr;
+
+use actix_web::http::{header, HeaderName, HeaderValue};
+use url::Url;
+
+use crate::args::{CliArgs, SameSite};
+use crate::upstream_path::public_path;
+
+/// Rewrites URLs in response headers which point at the upstream to point at us instead, much
+/// like nginx's `proxy_redirect`.
+///
+/// Absolute URLs are rewritten if they have the same origin as the upstream, absolute paths are
+/// always mapped back to the path the client would request. URLs outside of the part of the
+/// upstream we expose are left alone.
+pub struct LocationRewriter<'a> {
+    args: &'a CliArgs,
+    upstream: &'a Url,
+    /// Scheme and host the client used to reach us, eg. `https://example.com`.
+    public_origin: String,
+}
+
+impl<'a> LocationRewriter<'a> {
+    pub fn new(args: &'a CliArgs, upstream: &'a Url, scheme: &str, host: &str) -> Self {
+        LocationRewriter {
+            args,
+            upstream,
+            public_origin: format!("{}://{}", scheme, host),
+        }
+    }
+
+    /// The rewritten value of the header `name`, if it needs rewriting.
+    pub fn rewrite_header(&self, name: &HeaderName, value: &HeaderValue) -> Option<HeaderValue> {
+        let value = value.to_str().ok()?;
+        let rewritten = if name == header::LOCATION || name == header::CONTENT_LOCATION {
+            self.rewrite_url(value)?
+        } else if name == "refresh" {
+            self.rewrite_refresh(value)?
+        } else {
+            return None;
+        };
+        HeaderValue::from_str(&rewritten).ok()
+    }
+
+    fn rewrite_url(&self, url: &str) -> Option<String> {
+        if url.starts_with('/') && !url.starts_with("//") {
+            let (path, rest) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+            let path = public_path(self.args, self.upstream, path)?;
+            return Some(format!("{}{}", path, rest));
+        }
+
+        let url = Url::parse(url).ok()?;
+        let same_origin = url.scheme() == self.upstream.scheme()
+            && url
+                .host_str()
+                .zip(self.upstream.host_str())
+                .is_some_and(|(host, upstream_host)| host.eq_ignore_ascii_case(upstream_host))
+            && url.port_or_known_default() == self.upstream.port_or_known_default();
+        if !same_origin {
+            return None;
+        }
+        let mut rewritten = format!(
+            "{}{}",
+            self.public_origin,
+            public_path(self.args, self.upstream, url.path())?
+        );
+        if let Some(query) = url.query() {
+            rewritten.push('?');
+            rewritten.push_str(query);
+        }
+        if let Some(fragment) = url.fragment() {
+            rewritten.push('#');
+            rewritten.push_str(fragment);
+        }
+        Some(rewritten)
+    }
+
+    /// Rewrite the URL in a `Refresh` header like `5; url=http://localhost:3000/`.
+    fn rewrite_refresh(&self, value: &str) -> Option<String> {
+        let (delay, target) = value.split_once(';')?;
+        let (key, url) = target.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("url") {
+            return None;
+        }
+        let url = url.trim();
+        let quote = url.chars().next().filter(|c| *c == '"' || *c == '\'');
+        let url = match quote {
+            Some(quote) => url[1..].trim_end_matches(quote),
+            None => url,
+        };
+        let rewritten = self.rewrite_url(url)?;
+        Some(match quote {
+            Some(quote) => format!("{}; url={}{}{}", delay, quote, rewritten, quote),
+            None => format!("{}; url={}", delay, rewritten),
+        })
+    }
+}
+
+/// Rewrites `Set-Cookie` headers of the upstream so that browsers accept the cookies from us,
+/// much like nginx's `proxy_cookie_domain` and `proxy_cookie_path`.
+///
+/// `Domain` attributes not matching our host are replaced by it, or dropped when we are reached by
+/// IP address, and `Path` attributes are mapped back to the path the client would request. On top of that, `--cookie-secure` and
+/// `--cookie-samesite` force the respective attributes.
+pub struct CookieRewriter<'a> {
+    args: &'a CliArgs,
+    upstream: &'a Url,
+    /// Host name the client used to reach us, without the port.
+    public_host: &'a str,
+}
+
+impl<'a> CookieRewriter<'a> {
+    pub fn new(args: &'a CliArgs, upstream: &'a Url, host: &'a str) -> Self {
+        let public_host = match host.rfind(':') {
+            Some(colon) if !host[colon..].contains(']') => &host[..colon],
+            _ => host,
+        };
+        CookieRewriter {
+            args,
+            upstream,
+            public_host,
+        }
+    }
+
+    /// The rewritten value of the header `name`, if it needs rewriting.
+    pub fn rewrite_header(&self, name: &HeaderName, value: &HeaderValue) -> Option<HeaderValue> {
+        if name != header::SET_COOKIE {
+            return None;
+        }
+        HeaderValue::from_str(&self.rewrite_set_cookie(value.to_str().ok()?)?).ok()
+    }
+
+    fn rewrite_set_cookie(&self, cookie: &str) -> Option<String> {
+        let mut parts = cookie.split(';').map(str::trim);
+        let name_value = parts.next()?;
+        let mut attributes: Vec<String> = parts
+            .filter(|attribute| !attribute.is_empty())
+            .map(String::from)
+            .collect();
+        let mut changed = false;
+
+        if !self.args.no_rewrite_cookies {
+            // Cookies for IP addresses can only be host-only cookies.
+            if self.public_host_is_ip() {
+                let count = attributes.len();
+                attributes
+                    .retain(|attribute| !attribute_name(attribute).eq_ignore_ascii_case("domain"));
+                changed = attributes.len() != count;
+            }
+            for attribute in &mut attributes {
+                let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+                let rewritten = if name.trim().eq_ignore_ascii_case("domain") {
+                    self.rewrite_domain(value.trim())
+                } else if name.trim().eq_ignore_ascii_case("path") {
+                    self.rewrite_path(value.trim())
+                } else {
+                    None
+                };
+                if let Some(rewritten) = rewritten {
+                    *attribute = rewritten;
+                    changed = true;
+                }
+            }
+        }
+
+        // Browsers reject `SameSite=None` on cookies which aren't `Secure`.
+        let secure = self.args.cookie_secure || self.args.cookie_samesite == Some(SameSite::None);
+        if secure
+            && !attributes
+                .iter()
+                .any(|attribute| attribute.eq_ignore_ascii_case("secure"))
+        {
+            attributes.push("Secure".to_string());
+            changed = true;
+        }
+        if let Some(same_site) = self.args.cookie_samesite {
+            attributes
+                .retain(|attribute| !attribute_name(attribute).eq_ignore_ascii_case("samesite"));
+            attributes.push(format!("SameSite={}", same_site.as_str()));
+            changed = true;
+        }
+
+        if !changed {
+            return None;
+        }
+        let mut rewritten = name_value.to_string();
+        for attribute in attributes {
+            rewritten.push_str("; ");
+            rewritten.push_str(&attribute);
+        }
+        Some(rewritten)
+    }
+
+    fn public_host_is_ip(&self) -> bool {
+        let host = self
+            .public_host
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        host.parse::<IpAddr>().is_ok()
+    }
+
+    /// Replace `domain` by our host unless cookies for it are already sent to us.
+    fn rewrite_domain(&self, domain: &str) -> Option<String> {
+        let domain = domain.trim_start_matches('.').to_lowercase();
+        let host = self.public_host.to_lowercase();
+        if host == domain || host.ends_with(&format!(".{}", domain)) {
+            return None;
+        }
+        Some(format!("Domain={}", self.public_host))
+    }
+
+    fn rewrite_path(&self, path: &str) -> Option<String> {
+        let public_path = public_path(self.args, self.upstream, path)?;
+        // `/app` turns into `/api/` when mounted at `/api`, which wouldn't match `/api` itself.
+        let public_path = if public_path.len() > 1 && !path.ends_with('/') {
+            public_path.trim_end_matches('/')
+        } else {
+            &public_path
+        };
+        if public_path == path {
+            return None;
+        }
+        Some(format!("Path={}", public_path))
+    }
+}
+
+/// The name of a cookie attribute like `SameSite=Lax`.
+fn attribute_name(attribute: &str) -> &str {
+    attribute.split('=').next().unwrap_or_default().trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+
+    fn rewrite(args: &[&str], name: &str, value: &str) -> Option<String> {
+        let args = CliArgs::parse_from(["proxyboi"].iter().chain(args));
+        let upstream = args.upstream.clone().unwrap();
+        let rewriter = LocationRewriter::new(&args, &upstream, "https", "example.com");
+        rewriter
+            .rewrite_header(
+                &HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                &HeaderValue::from_str(value).unwrap(),
+            )
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_absolute_location() {
+        let args = ["http://localhost:3000"];
+        assert_eq!(
+            rewrite(
+                &args,
+                "location",
+                "http://localhost:3000/login?next=%2F#top"
+            )
+            .unwrap(),
+            "https://example.com/login?next=%2F#top"
+        );
+        assert_eq!(
+            rewrite(&args, "location", "http://LOCALHOST:3000/").unwrap(),
+            "https://example.com/"
+        );
+        assert_eq!(rewrite(&args, "location", "http://localhost:3001/"), None);
+        assert_eq!(rewrite(&args, "location", "https://localhost:3000/"), None);
+        assert_eq!(rewrite(&args, "location", "https://example.org/"), None);
+        assert_eq!(rewrite(&args, "location", "//localhost:3000/"), None);
+    }
+
+    #[test]
+    fn test_mapped_paths() {
+        let args = [
+            "http://localhost/app/",
+            "--strip-path-prefix",
+            "/api",
+            "--add-path-prefix",
+            "/v1",
+        ];
+        assert_eq!(
+            rewrite(&args, "location", "http://localhost/app/v1/users").unwrap(),
+            "https://example.com/api/users"
+        );
+        assert_eq!(
+            rewrite(&args, "content-location", "/app/v1/users?page=2").unwrap(),
+            "/api/users?page=2"
+        );
+        assert_eq!(rewrite(&args, "location", "/other"), None);
+        assert_eq!(rewrite(&args, "location", "http://localhost/app/v2/"), None);
+    }
+
+    #[test]
+    fn test_refresh() {
+        let args = ["http://localhost:3000"];
+        assert_eq!(
+            rewrite(&args, "refresh", "5; URL=http://localhost:3000/done").unwrap(),
+            "5; url=https://example.com/done"
+        );
+        assert_eq!(
+            rewrite(&args, "refresh", "0;url='http://localhost:3000/'").unwrap(),
+            "0; url='https://example.com/'"
+        );
+        assert_eq!(rewrite(&args, "refresh", "5"), None);
+        assert_eq!(
+            rewrite(&args, "refresh", "5; url=https://example.org/"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_other_headers() {
+        assert_eq!(
+            rewrite(&["http://localhost:3000"], "link", "http://localhost:3000/"),
+            None
+        );
+    }
+
+    fn rewrite_cookie(args: &[&str], host: &str, cookie: &str) -> Option<String> {
+        let args = CliArgs::parse_from(["proxyboi"].iter().chain(args));
+        let upstream = args.upstream.clone().unwrap();
+        CookieRewriter::new(&args, &upstream, host).rewrite_set_cookie(cookie)
+    }
+
+    #[test]
+    fn test_cookie_domain() {
+        let args = ["http://backend.internal:3000"];
+        assert_eq!(
+            rewrite_cookie(
+                &args,
+                "example.com:8443",
+                "id=1; Domain=backend.internal; HttpOnly"
+            )
+            .unwrap(),
+            "id=1; Domain=example.com; HttpOnly"
+        );
+        assert_eq!(
+            rewrite_cookie(&args, "app.example.com", "id=1; domain=.Example.com"),
+            None
+        );
+        assert_eq!(
+            rewrite_cookie(&args, "[::1]:8080", "id=1; Domain=backend.internal").unwrap(),
+            "id=1"
+        );
+        assert_eq!(
+            rewrite_cookie(&args, "192.0.2.1", "id=1; Domain=backend.internal; Secure").unwrap(),
+            "id=1; Secure"
+        );
+        assert_eq!(
+            rewrite_cookie(&args, "192.0.2.1:8080", "id=1; Path=/"),
+            None
+        );
+        assert_eq!(rewrite_cookie(&args, "example.com", "id=1"), None);
+    }
+
+    #[test]
+    fn test_cookie_path() {
+        let args = ["http://localhost:3000/app/", "--strip-path-prefix", "/api"];
+        assert_eq!(
+            rewrite_cookie(&args, "example.com", "id=1; Path=/app").unwrap(),
+            "id=1; Path=/api"
+        );
+        assert_eq!(
+            rewrite_cookie(&args, "example.com", "id=1; Path=/app/admin/").unwrap(),
+            "id=1; Path=/api/admin/"
+        );
+        assert_eq!(rewrite_cookie(&args, "example.com", "id=1; Path=/"), None);
+        assert_eq!(
+            rewrite_cookie(
+                &["http://localhost:3000/app/", "--no-rewrite-cookies"],
+                "example.com",
+                "id=1; Path=/app"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cookie_forced_attributes() {
+        let args = ["http://localhost:3000", "--cookie-secure"];
+        assert_eq!(
+            rewrite_cookie(&args, "example.com", "id=1").unwrap(),
+            "id=1; Secure"
+        );
+        assert_eq!(rewrite_cookie(&args, "example.com", "id=1; secure"), None);
+
+        let args = ["http://localhost:3000", "--cookie-samesite", "none"];
+        assert_eq!(
+            rewrite_cookie(&args, "example.com", "id=a=b; SameSite=Lax; Path=/").unwrap(),
+            "id=a=b; Path=/; Secure; SameSite=None"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::cmp;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// A stream which replays some already consumed bytes before continuing to read from the
+/// underlying stream.
+///
+/// This allows us to look at the start of a connection (eg. to detect the protocol spoken) and
+/// then pretend nothing happened.
+pub struct Rewind<T> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: T,
+}
+
+impl<T> Rewind<T> {
+    pub fn new(inner: T, prefix: Vec<u8>) -> Self {
+        Rewind {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+/// Read from `io` until it's clear whether the stream starts with `expected`.
+///
+/// Returns whether it does and a stream to read everything from the start.
+pub async fn starts_with<T>(mut io: T, expected: &[u8]) -> io::Result<(bool, Rewind<T>)>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut prefix = vec![0; expected.len()];
+    let mut len = 0;
+    while len < expected.len() && prefix[..len] == expected[..len] {
+        let read = io.read(&mut prefix[len..]).await?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    prefix.truncate(len);
+    Ok((prefix == expected, Rewind::new(io, prefix)))
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pos < self.prefix.len() {
+            let len = cmp::min(buf.len(), self.prefix.len() - self.pos);
+            buf[..len].copy_from_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            return Poll::Ready(Ok(len));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[actix_rt::test]
+    async fn test_matching_prefix_is_replayed() {
+        let input: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\nrest";
+        let (matched, mut stream) = starts_with(input, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = vec![];
+        stream.read_to_end(&mut output).await.unwrap();
+        assert!(matched);
+        assert_eq!(output, input);
+    }
+
+    #[actix_rt::test]
+    async fn test_mismatch_is_replayed() {
+        let input: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        let (matched, mut stream) = starts_with(input, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = vec![];
+        stream.read_to_end(&mut output).await.unwrap();
+        assert!(!matched);
+        assert_eq!(output, input);
+    }
+
+    #[actix_rt::test]
+    async fn test_short_stream() {
+        let input: &[u8] = b"PRI";
+        let (matched, mut stream) = starts_with(input, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = vec![];
+        stream.read_to_end(&mut output).await.unwrap();
+        assert!(!matched);
+        assert_eq!(output, input);
+    }
+}
@@ -0,0 +1,182 @@
+use std::str::FromStr;
+
+use actix_web::http::StatusCode;
+use regex::Regex;
+
+/// What to do with requests matching a rewrite rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RewriteAction {
+    /// Send the rewritten request to the upstream
+    Internal,
+
+    /// Redirect the client to the rewritten URL with this status
+    Redirect(StatusCode),
+}
+
+/// A rule given to `--rewrite` in the format "REGEX REPLACEMENT [redirect|permanent]".
+///
+/// The regex is matched against the path and query of a request, the replacement may refer to
+/// capture groups as `$1` or `${name}`.
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pattern: Regex,
+    replacement: String,
+    action: RewriteAction,
+}
+
+impl FromStr for RewriteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split_whitespace().collect::<Vec<_>>();
+        let (pattern, replacement, action) = match parts[..] {
+            [pattern, replacement] => (pattern, replacement, RewriteAction::Internal),
+            [pattern, replacement, "redirect"] => (
+                pattern,
+                replacement,
+                RewriteAction::Redirect(StatusCode::FOUND),
+            ),
+            [pattern, replacement, "permanent"] => (
+                pattern,
+                replacement,
+                RewriteAction::Redirect(StatusCode::MOVED_PERMANENTLY),
+            ),
+            _ => return Err(
+                "Wrong rewrite rule format (expected \"REGEX REPLACEMENT [redirect|permanent]\")"
+                    .to_string(),
+            ),
+        };
+        Ok(RewriteRule {
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement.to_string(),
+            action,
+        })
+    }
+}
+
+/// Outcome of applying the rewrite rules to a request.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rewrite {
+    /// Request this path (and query) from the upstream instead
+    Internal { path: String, query: Option<String> },
+
+    /// Redirect the client to this location
+    Redirect {
+        status: StatusCode,
+        location: String,
+    },
+}
+
+/// Apply the first of `rules` matching `path_and_query`, if any.
+pub fn rewrite(rules: &[RewriteRule], path_and_query: &str) -> Option<Rewrite> {
+    let (rule, captures) = rules
+        .iter()
+        .find_map(|rule| Some((rule, rule.pattern.captures(path_and_query)?)))?;
+    let mut rewritten = String::new();
+    captures.expand(&rule.replacement, &mut rewritten);
+    // Only the matched part is replaced, like with `Regex::replace()`.
+    let matched = captures.get(0).expect("Group 0 is the whole match");
+    let rewritten = format!(
+        "{}{}{}",
+        &path_and_query[..matched.start()],
+        rewritten,
+        &path_and_query[matched.end()..]
+    );
+
+    Some(match rule.action {
+        RewriteAction::Internal => {
+            let (path, query) = match rewritten.split_once('?') {
+                Some((path, query)) => (path.to_string(), Some(query.to_string())),
+                None => (rewritten, None),
+            };
+            let path = if path.starts_with('/') {
+                path
+            } else {
+                format!("/{}", path)
+            };
+            Rewrite::Internal { path, query }
+        }
+        RewriteAction::Redirect(status) => Rewrite::Redirect {
+            status,
+            location: rewritten,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn rules(rules: &[&str]) -> Vec<RewriteRule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    fn internal(path: &str, query: Option<&str>) -> Option<Rewrite> {
+        Some(Rewrite::Internal {
+            path: path.to_string(),
+            query: query.map(|query| query.to_string()),
+        })
+    }
+
+    #[test]
+    fn test_internal_rewrite() {
+        let rules = rules(&[
+            r"^/old/(.*)$ /new/$1",
+            r"^/user/(?P<id>\d+) /users?id=${id}",
+        ]);
+        assert_eq!(
+            rewrite(&rules, "/old/a/b?x=1"),
+            internal("/new/a/b", Some("x=1"))
+        );
+        assert_eq!(
+            rewrite(&rules, "/user/42"),
+            internal("/users", Some("id=42"))
+        );
+        assert_eq!(rewrite(&rules, "/other"), None);
+    }
+
+    #[test]
+    fn test_partial_match() {
+        let rules = rules(&["/v1/ /v2/"]);
+        assert_eq!(
+            rewrite(&rules, "/api/v1/users"),
+            internal("/api/v2/users", None)
+        );
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let rules = rules(&["^/a /first", "^/a /second"]);
+        assert_eq!(rewrite(&rules, "/a"), internal("/first", None));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let rules = rules(&[
+            "^/legacy/(.*) /v2/$1 redirect",
+            "^/gone https://example.com/ permanent",
+        ]);
+        assert_eq!(
+            rewrite(&rules, "/legacy/page?x=1"),
+            Some(Rewrite::Redirect {
+                status: StatusCode::FOUND,
+                location: "/v2/page?x=1".to_string()
+            })
+        );
+        assert_eq!(
+            rewrite(&rules, "/gone"),
+            Some(Rewrite::Redirect {
+                status: StatusCode::MOVED_PERMANENTLY,
+                location: "https://example.com/".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!("^/a".parse::<RewriteRule>().is_err());
+        assert!("^/a /b sideways".parse::<RewriteRule>().is_err());
+        assert!("^/(a /b".parse::<RewriteRule>().is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_http::{body::MessageBody, error::DispatchError, HttpService, Protocol};
+use actix_rt::net::TcpStream;
+#[cfg(unix)]
+use actix_rt::net::UnixStream;
+use actix_server::ServerBuilder;
+use actix_service::boxed::{self, BoxServiceFactory};
+use actix_service::{fn_service, map_config, pipeline_factory, ServiceFactory};
+use actix_web::dev::{AppConfig, ServiceRequest, ServiceResponse};
+use actix_web::App;
+use rustls::{ServerConfig, Session};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+use crate::client::ConnectionClient;
+use crate::forward_proxy::ForwardProxy;
+use crate::grpc::GrpcProxy;
+use crate::listener::Listener;
+use crate::proxy_protocol::read_header;
+use crate::rewind::{starts_with, Rewind};
+
+/// Connection preface sent by HTTP/2 clients (RFC 7540 section 3.5).
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How long to wait for the first bytes of a plain HTTP connection or for the TLS handshake.
+///
+/// Same as the client timeout used for HTTP/1.
+pub const PROTOCOL_DETECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ALPN protocol used by ACME TLS-ALPN-01 validation servers (RFC 8737).
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+/// Information about the listener a request came in on.
+///
+/// We build the HTTP services ourselves instead of going through `HttpServer`, so actix-web has
+/// no idea whether a connection is secure. Handlers should ask this instead of
+/// `ConnectionInfo::scheme()`.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerInfo {
+    pub secure: bool,
+}
+
+impl ListenerInfo {
+    pub fn scheme(&self) -> &'static str {
+        if self.secure {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
+/// How to handle connections on a listener before they reach the HTTP service.
+#[derive(Clone, Default)]
+pub struct ListenerOptions {
+    /// Serve HTTP/2 connections with this gRPC proxy
+    pub grpc: Option<GrpcProxy>,
+
+    /// Expect a PROXY protocol header at the start of every connection
+    pub proxy_protocol: bool,
+
+    /// Handle CONNECT requests with this forward proxy
+    pub forward_proxy: Option<ForwardProxy>,
+}
+
+/// Build the HTTP service for a single connection out of an `App`.
+///
+/// This mirrors what `HttpServer` does internally, including its default timeouts. With a
+/// `forward_proxy`, CONNECT requests (and any other protocol upgrades) are handed over to it
+/// along with the connection.
+fn http_service<T, B, S>(
+    app: App<T, B>,
+    forward_proxy: Option<ForwardProxy>,
+) -> BoxServiceFactory<(), (S, Protocol, Option<SocketAddr>), (), DispatchError, ()>
+where
+    T: ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+{
+    let builder = HttpService::build()
+        .client_timeout(5000)
+        .client_disconnect(5000)
+        .on_connect(|_: &S| ConnectionClient::default());
+    let app = map_config(app, |_| AppConfig::default());
+    match forward_proxy {
+        Some(forward_proxy) => boxed::factory(
+            builder
+                .upgrade(fn_service(move |(request, framed)| {
+                    forward_proxy.clone().connect(request, framed)
+                }))
+                .finish(app),
+        ),
+        None => boxed::factory(builder.finish(app)),
+    }
+}
+
+/// Hand HTTP/2 connections over to the gRPC proxy if there is one.
+///
+/// Returns the connection back if it should be served by the HTTP service. Connections handled by
+/// the gRPC proxy end in an error so that the HTTP service never sees them.
+async fn serve_grpc<S>(
+    grpc: Option<GrpcProxy>,
+    io: S,
+    protocol: Protocol,
+    peer_addr: Option<SocketAddr>,
+    listener: ListenerInfo,
+) -> Result<(S, Protocol, Option<SocketAddr>), DispatchError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+{
+    match (grpc, protocol) {
+        (Some(grpc), Protocol::Http2) => {
+            grpc.serve(io, peer_addr, listener).await;
+            Err(DispatchError::Io(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Connection handled by the gRPC proxy",
+            )))
+        }
+        _ => Ok((io, protocol, peer_addr)),
+    }
+}
+
+/// A connection accepted on one of our listeners.
+pub trait Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static {
+    /// Address of the client, if there is one.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Stream for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Figure out the address of the client on the other end of `io`.
+///
+/// With `proxy_protocol`, the address is taken from the PROXY protocol header which is expected
+/// at the start of the connection.
+pub async fn client_addr<S: Stream>(
+    io: &mut S,
+    proxy_protocol: bool,
+) -> io::Result<Option<SocketAddr>> {
+    if !proxy_protocol {
+        return Ok(io.peer_addr());
+    }
+    let addrs = timeout(PROTOCOL_DETECTION_TIMEOUT, read_header(io))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    Ok(addrs.map(|addrs| addrs.source).or_else(|| io.peer_addr()))
+}
+
+/// Find out whether the client on a plain connection speaks HTTP/2 with prior knowledge, which
+/// it does if it starts with the connection preface.
+///
+/// Returns the protocol and a stream to read the connection from the start.
+async fn detect_protocol<S>(io: S) -> io::Result<(Protocol, Rewind<S>)>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    let (is_h2, io) = timeout(PROTOCOL_DETECTION_TIMEOUT, starts_with(io, H2_PREFACE))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let protocol = if is_h2 {
+        Protocol::Http2
+    } else {
+        Protocol::Http1
+    };
+    Ok((protocol, io))
+}
+
+/// Serve plain HTTP on connections of type `S`, see `bind_http()`.
+fn plain_service<S, T, B>(
+    app: App<T, B>,
+    options: ListenerOptions,
+) -> impl ServiceFactory<Config = (), Request = S, Response = (), Error = DispatchError, InitError = ()>
+where
+    S: Stream,
+    T: ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let listener = ListenerInfo { secure: false };
+    let forward_proxy = options.forward_proxy.clone();
+    pipeline_factory(fn_service(move |mut io: S| {
+        let options = options.clone();
+        async move {
+            let peer_addr = client_addr(&mut io, options.proxy_protocol).await?;
+            let (protocol, io) = detect_protocol(io).await?;
+            serve_grpc(options.grpc, io, protocol, peer_addr, listener).await
+        }
+    }))
+    .and_then(http_service(app.data(listener), forward_proxy))
+}
+
+/// Serve HTTPS on connections of type `S`, see `bind_https()`.
+fn tls_service<S, T, B>(
+    app: App<T, B>,
+    tls_config: Arc<ServerConfig>,
+    options: ListenerOptions,
+) -> impl ServiceFactory<Config = (), Request = S, Response = (), Error = DispatchError, InitError = ()>
+where
+    S: Stream,
+    T: ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let listener = ListenerInfo { secure: true };
+    let forward_proxy = options.forward_proxy.clone();
+    let acceptor = TlsAcceptor::from(tls_config);
+    pipeline_factory(fn_service(move |mut io: S| {
+        let acceptor = acceptor.clone();
+        let options = options.clone();
+        async move {
+            let peer_addr = client_addr(&mut io, options.proxy_protocol).await?;
+            let tls_stream = timeout(PROTOCOL_DETECTION_TIMEOUT, acceptor.accept(io))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            let protocol = match tls_stream.get_ref().1.get_alpn_protocol() {
+                Some(b"h2") => Protocol::Http2,
+                Some(ACME_TLS_ALPN_PROTOCOL) => {
+                    return Err(DispatchError::Io(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "ACME TLS-ALPN-01 validation connection",
+                    )));
+                }
+                _ => Protocol::Http1,
+            };
+            serve_grpc(options.grpc, tls_stream, protocol, peer_addr, listener).await
+        }
+    }))
+    .and_then(http_service(app.data(listener), forward_proxy))
+}
+
+/// Add a plain HTTP listener serving the `App` returned by `factory`.
+///
+/// Clients may speak HTTP/2 with prior knowledge (h2c) which is detected by its connection
+/// preface.
+pub fn bind_http<F, T, B>(
+    builder: ServerBuilder,
+    listener: Listener,
+    options: ListenerOptions,
+    factory: F,
+) -> io::Result<ServerBuilder>
+where
+    F: Fn() -> App<T, B> + Send + Clone + 'static,
+    T: ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let name = format!("proxyboi-http-{}", listener);
+    match listener {
+        Listener::Addr(addr) => builder.bind(name, addr, move || {
+            plain_service::<TcpStream, _, _>(factory(), options.clone())
+        }),
+        Listener::Tcp(listener) => builder.listen(name, listener, move || {
+            plain_service::<TcpStream, _, _>(factory(), options.clone())
+        }),
+        #[cfg(unix)]
+        Listener::Unix(listener) => builder.listen_uds(name, listener, move || {
+            plain_service::<UnixStream, _, _>(factory(), options.clone())
+        }),
+    }
+}
+
+/// Add a TLS listener serving the `App` returned by `factory`.
+///
+/// The protocol is picked according to the negotiated ALPN protocol. Connections which negotiated
+/// the ACME TLS-ALPN-01 protocol are closed right after the handshake as the validation server
+/// only cares about the certificate we presented.
+pub fn bind_https<F, T, B>(
+    builder: ServerBuilder,
+    listener: Listener,
+    tls_config: Arc<ServerConfig>,
+    options: ListenerOptions,
+    factory: F,
+) -> io::Result<ServerBuilder>
+where
+    F: Fn() -> App<T, B> + Send + Clone + 'static,
+    T: ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let name = format!("proxyboi-https-{}", listener);
+    match listener {
+        Listener::Addr(addr) => builder.bind(name, addr, move || {
+            tls_service::<TcpStream, _, _>(factory(), tls_config.clone(), options.clone())
+        }),
+        Listener::Tcp(listener) => builder.listen(name, listener, move || {
+            tls_service::<TcpStream, _, _>(factory(), tls_config.clone(), options.clone())
+        }),
+        #[cfg(unix)]
+        Listener::Unix(listener) => builder.listen_uds(name, listener, move || {
+            tls_service::<UnixStream, _, _>(factory(), tls_config.clone(), options.clone())
+        }),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::args::CliArgs;
+    use crate::client::client_config;
+
+    /// The protocol detected for a client sending `data`, and what the service gets to read.
+    async fn detect(data: &[u8]) -> (Protocol, Vec<u8>) {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(data).await.unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let (protocol, mut io) = detect_protocol(server).await.unwrap();
+        let mut read = vec![];
+        io.read_to_end(&mut read).await.unwrap();
+        (protocol, read)
+    }
+
+    #[actix_rt::test]
+    async fn test_detect_http2() {
+        let request = [H2_PREFACE, b"\0\0\0\x04\0\0\0\0\0"].concat();
+        assert_eq!(detect(&request).await, (Protocol::Http2, request));
+    }
+
+    #[actix_rt::test]
+    async fn test_detect_http1() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        assert_eq!(detect(&request).await, (Protocol::Http1, request));
+
+        // Clients going away before sending a whole preface don't speak HTTP/2.
+        let request = H2_PREFACE[..10].to_vec();
+        assert_eq!(detect(&request).await, (Protocol::Http1, request));
+    }
+
+    #[actix_rt::test]
+    async fn test_detect_http1_without_waiting() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"GET").await.unwrap();
+        let (protocol, _) = detect_protocol(server).await.unwrap();
+        assert_eq!(protocol, Protocol::Http1);
+    }
+
+    #[actix_rt::test]
+    async fn test_detection_timeout() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(&H2_PREFACE[..10]).await.unwrap();
+        // The paused clock skips ahead to the timeout as nothing else is going on.
+        tokio::time::pause();
+        let started = tokio::time::Instant::now();
+        let result = detect_protocol(server).await;
+        let error = result.map(|(protocol, _)| protocol).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= PROTOCOL_DETECTION_TIMEOUT);
+    }
+
+    #[actix_rt::test]
+    async fn test_grpc_hand_off() {
+        let args = CliArgs::parse_from(["proxyboi", "--grpc", "http://localhost:3000"]);
+        let grpc = GrpcProxy::new(args.clone(), client_config(&args));
+        let listener = ListenerInfo { secure: false };
+
+        // Without the gRPC proxy, all connections go to the HTTP service.
+        let (_client, server) = UnixStream::pair().unwrap();
+        let result = serve_grpc(None, server, Protocol::Http2, None, listener).await;
+        assert_eq!(result.unwrap().1, Protocol::Http2);
+
+        let (_client, server) = UnixStream::pair().unwrap();
+        let result = serve_grpc(Some(grpc.clone()), server, Protocol::Http1, None, listener).await;
+        assert_eq!(result.unwrap().1, Protocol::Http1);
+
+        // The client going away right away ends the connection in the gRPC proxy, which must not
+        // be handed back to the HTTP service.
+        let (client, server) = UnixStream::pair().unwrap();
+        drop(client);
+        match serve_grpc(Some(grpc), server, Protocol::Http2, None, listener).await {
+            Err(DispatchError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted),
+            _ => panic!("HTTP/2 connection wasn't handed over to the gRPC proxy"),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use url::Url;
+
+use crate::args::SniRoute;
+use crate::rewind::Rewind;
+
+/// TLS record content type of handshake messages.
+const HANDSHAKE: u8 = 22;
+
+/// Handshake message type of the ClientHello.
+const CLIENT_HELLO: u8 = 1;
+
+/// Extension type of the server name indication (RFC 6066 section 3).
+const SERVER_NAME_EXTENSION: u16 = 0;
+
+/// Name type of DNS host names in the server name extension.
+const HOST_NAME: u8 = 0;
+
+/// Give up on finding the server name after this many bytes.
+///
+/// ClientHellos are usually much smaller but may grow with many cipher suites or large key shares.
+const MAX_CLIENT_HELLO_LENGTH: usize = 64 * 1024;
+
+/// Outcome of looking for the server name at the start of a connection.
+#[derive(Debug, PartialEq, Eq)]
+enum ClientHello {
+    /// More data is needed to tell.
+    Incomplete,
+
+    /// All we're going to find, which is the server name if the client sent one.
+    Done(Option<String>),
+}
+
+/// Cursor over a byte slice where every read fails once the slice is exhausted.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|bytes| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    /// Read a vector prefixed by its length in `len_bytes` bytes.
+    fn vec(&mut self, len_bytes: usize) -> Option<Reader<'a>> {
+        let len = match len_bytes {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => unreachable!("Only 1 and 2 byte lengths are used"),
+        };
+        self.bytes(len).map(Reader)
+    }
+}
+
+/// Look for the server name in the TLS records at the start of a connection.
+///
+/// The ClientHello may be split into several records which are joined first.
+fn parse(data: &[u8]) -> ClientHello {
+    let mut records = Reader(data);
+    let mut handshake = vec![];
+    loop {
+        match records.u8() {
+            Some(HANDSHAKE) => {}
+            Some(_) => return ClientHello::Done(None),
+            None => return ClientHello::Incomplete,
+        }
+        let fragment = match (records.bytes(2), records.vec(2)) {
+            (Some(_version), Some(fragment)) => fragment,
+            _ => return ClientHello::Incomplete,
+        };
+        handshake.extend_from_slice(fragment.0);
+
+        let mut message = Reader(&handshake);
+        match (message.u8(), message.u24()) {
+            (Some(CLIENT_HELLO), Some(len)) if message.0.len() >= len => {
+                return ClientHello::Done(server_name(Reader(&message.0[..len])));
+            }
+            (Some(CLIENT_HELLO), _) | (None, _) => {}
+            (Some(_), _) => return ClientHello::Done(None),
+        }
+    }
+}
+
+/// Extract the server name from the body of a ClientHello message.
+fn server_name(mut client_hello: Reader) -> Option<String> {
+    // Legacy version and random
+    client_hello.bytes(2 + 32)?;
+    // Session ID, cipher suites and compression methods
+    client_hello.vec(1)?;
+    client_hello.vec(2)?;
+    client_hello.vec(1)?;
+
+    let mut extensions = client_hello.vec(2)?;
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut extension = extensions.vec(2)?;
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let mut names = extension.vec(2)?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec(2)?;
+            if name_type == HOST_NAME {
+                return std::str::from_utf8(name.0)
+                    .ok()
+                    .map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// Read the TLS ClientHello at the start of `io` and return the server name it asks for.
+///
+/// Nothing is consumed as far as the caller is concerned: the returned stream replays everything
+/// read so the connection can be passed on untouched. Connections not starting with a
+/// ClientHello or without a server name give `None`.
+pub async fn peek_server_name<T>(mut io: T) -> io::Result<(Option<String>, Rewind<T>)>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut data = vec![];
+    let mut buf = [0; 4096];
+    let server_name = loop {
+        if let ClientHello::Done(server_name) = parse(&data) {
+            break server_name;
+        }
+        if data.len() >= MAX_CLIENT_HELLO_LENGTH {
+            break None;
+        }
+        let read = io.read(&mut buf).await?;
+        if read == 0 {
+            break None;
+        }
+        data.extend_from_slice(&buf[..read]);
+    };
+    Ok((server_name, Rewind::new(io, data)))
+}
+
+/// Find the upstream for `server_name` in `routes`.
+///
+/// Exact matches win over wildcard routes like `*.example.com`, which match exactly one
+/// additional label.
+pub fn route<'a>(routes: &'a [SniRoute], server_name: &str) -> Option<&'a Url> {
+    let exact = routes
+        .iter()
+        .find(|route| route.server_name.eq_ignore_ascii_case(server_name));
+    let wildcard = || {
+        let (_, parent) = server_name.split_once('.')?;
+        routes.iter().find(|route| {
+            route
+                .server_name
+                .strip_prefix("*.")
+                .is_some_and(|suffix| suffix.eq_ignore_ascii_case(parent))
+        })
+    };
+    exact.or_else(wildcard).map(|route| &route.upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Build a TLS 1.2 style ClientHello record with the given extensions.
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        // Session ID, one cipher suite, null compression
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        let mut extension_data = vec![];
+        for (extension_type, data) in extensions {
+            extension_data.extend_from_slice(&extension_type.to_be_bytes());
+            extension_data.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extension_data.extend_from_slice(data);
+        }
+        body.extend_from_slice(&(extension_data.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extension_data);
+
+        let mut message = vec![CLIENT_HELLO];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+        record(&message)
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        let mut record = vec![HANDSHAKE, 3, 1];
+        record.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+        record.extend_from_slice(fragment);
+        record
+    }
+
+    fn sni(name: &str) -> (u16, Vec<u8>) {
+        let mut entry = vec![HOST_NAME];
+        entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        let mut data = (entry.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&entry);
+        (SERVER_NAME_EXTENSION, data)
+    }
+
+    fn routes(routes: &[(&str, &str)]) -> Vec<SniRoute> {
+        routes
+            .iter()
+            .map(|(server_name, upstream)| SniRoute {
+                server_name: server_name.to_string(),
+                upstream: upstream.parse().unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_server_name() {
+        let data = client_hello(&[(10, vec![0, 2, 0, 29]), sni("Db.Example.com")]);
+        assert_eq!(
+            parse(&data),
+            ClientHello::Done(Some("db.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_no_server_name() {
+        let data = client_hello(&[(10, vec![0, 2, 0, 29])]);
+        assert_eq!(parse(&data), ClientHello::Done(None));
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let data = client_hello(&[sni("example.com")]);
+        for len in [0, 3, 5, 20, data.len() - 1] {
+            assert_eq!(parse(&data[..len]), ClientHello::Incomplete);
+        }
+    }
+
+    #[test]
+    fn test_split_across_records() {
+        let data = client_hello(&[sni("example.com")]);
+        let message = &data[5..];
+        let mut split = record(&message[..10]);
+        split.extend_from_slice(&record(&message[10..]));
+        assert_eq!(
+            parse(&split),
+            ClientHello::Done(Some("example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_not_tls() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n"), ClientHello::Done(None));
+    }
+
+    #[actix_rt::test]
+    async fn test_peek_replays_everything() {
+        let mut input = client_hello(&[sni("example.com")]);
+        input.extend_from_slice(b"rest");
+        let (server_name, mut stream) = peek_server_name(&input[..]).await.unwrap();
+        let mut output = vec![];
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(server_name.as_deref(), Some("example.com"));
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_route() {
+        let routes = routes(&[
+            ("*.example.com", "tcp://wildcard:1"),
+            ("db.example.com", "tcp://db:2"),
+        ]);
+        let upstream = |server_name| route(&routes, server_name).map(|url| url.as_str());
+        assert_eq!(upstream("db.example.com"), Some("tcp://db:2"));
+        assert_eq!(upstream("DB.example.com"), Some("tcp://db:2"));
+        assert_eq!(upstream("cache.example.com"), Some("tcp://wildcard:1"));
+        assert_eq!(upstream("a.b.example.com"), None);
+        assert_eq!(upstream("example.com"), None);
+    }
+}
@@ -0,0 +1,500 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use actix_rt::net::TcpStream;
+#[cfg(unix)]
+use actix_rt::net::UnixStream;
+use actix_server::ServerBuilder;
+use actix_service::{fn_service, ServiceFactory};
+use log::{error, info, warn};
+use ring::constant_time::verify_slices_are_equal;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::args::CliArgs;
+use crate::forward_proxy::check_destination;
+use crate::listener::Listener;
+use crate::logging::{log_tcp_connection_closed, log_tcp_connection_opened};
+use crate::outbound::Outbound;
+use crate::server::{client_addr, Stream, PROTOCOL_DETECTION_TIMEOUT};
+use crate::tcp::splice;
+
+/// Protocol version sent at the start of every message (RFC 1928).
+const VERSION: u8 = 5;
+
+/// Version of the username/password subnegotiation (RFC 1929).
+const AUTH_VERSION: u8 = 1;
+
+/// Authentication methods.
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+/// The CONNECT command, the only one we support.
+const CONNECT: u8 = 1;
+
+/// Address types.
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Reply codes.
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const NOT_ALLOWED: u8 = 2;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+
+/// Address to connect to through a SOCKS5 proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// Encode as address type, address and port.
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        let port = match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                bytes.push(IPV4);
+                bytes.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                bytes.push(IPV6);
+                bytes.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            TargetAddr::Domain(domain, port) => {
+                let len = u8::try_from(domain.len())
+                    .map_err(|_| io::Error::other("Domain name too long for SOCKS5"))?;
+                bytes.push(DOMAIN_NAME);
+                bytes.push(len);
+                bytes.extend_from_slice(domain.as_bytes());
+                *port
+            }
+        };
+        bytes.extend_from_slice(&port.to_be_bytes());
+        Ok(bytes)
+    }
+
+    /// Read address type, address and port.
+    async fn read<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Self> {
+        let addr = match io.read_u8().await? {
+            IPV4 => {
+                let mut ip = [0; 4];
+                io.read_exact(&mut ip).await?;
+                TargetAddr::Ip(SocketAddr::new(IpAddr::from(ip), 0))
+            }
+            IPV6 => {
+                let mut ip = [0; 16];
+                io.read_exact(&mut ip).await?;
+                TargetAddr::Ip(SocketAddr::new(IpAddr::from(ip), 0))
+            }
+            DOMAIN_NAME => {
+                let mut domain = vec![0; io.read_u8().await? as usize];
+                io.read_exact(&mut domain).await?;
+                let domain =
+                    String::from_utf8(domain).map_err(|_| invalid("domain name is not UTF-8"))?;
+                TargetAddr::Domain(domain, 0)
+            }
+            address_type => return Err(invalid(&format!("address type {}", address_type))),
+        };
+        let port = io.read_u16().await?;
+        Ok(match addr {
+            TargetAddr::Ip(addr) => TargetAddr::Ip(SocketAddr::new(addr.ip(), port)),
+            TargetAddr::Domain(domain, _) => TargetAddr::Domain(domain, port),
+        })
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid SOCKS5 message: {}", message),
+    )
+}
+
+/// Description of a reply code as defined in RFC 1928 section 6.
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        GENERAL_FAILURE => "general SOCKS server failure",
+        NOT_ALLOWED => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        HOST_UNREACHABLE => "host unreachable",
+        CONNECTION_REFUSED => "connection refused",
+        6 => "TTL expired",
+        COMMAND_NOT_SUPPORTED => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Ask the SOCKS5 proxy at the other end of `io` to connect to `target`.
+///
+/// Once this returns, `io` is connected to `target`.
+pub async fn connect<T>(
+    io: &mut T,
+    target: &TargetAddr,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = if credentials.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTH
+    };
+    io.write_all(&[VERSION, 1, method]).await?;
+    let mut reply = [0; 2];
+    io.read_exact(&mut reply).await?;
+    match reply {
+        [VERSION, NO_ACCEPTABLE_METHODS] => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy doesn't accept our authentication method",
+            ))
+        }
+        [VERSION, selected] if selected == method => {}
+        _ => return Err(invalid("unexpected method selection")),
+    }
+
+    if let Some((username, password)) = credentials {
+        let username_len = u8::try_from(username.len())
+            .map_err(|_| io::Error::other("SOCKS5 username too long"))?;
+        let password_len = u8::try_from(password.len())
+            .map_err(|_| io::Error::other("SOCKS5 password too long"))?;
+        let mut request = vec![AUTH_VERSION, username_len];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password_len);
+        request.extend_from_slice(password.as_bytes());
+        io.write_all(&request).await?;
+        let mut reply = [0; 2];
+        io.read_exact(&mut reply).await?;
+        if reply[1] != SUCCEEDED {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy rejected our credentials",
+            ));
+        }
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    request.extend_from_slice(&target.to_bytes()?);
+    io.write_all(&request).await?;
+    let mut reply = [0; 3];
+    io.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid("unexpected version in reply"));
+    }
+    if reply[1] != SUCCEEDED {
+        return Err(io::Error::other(format!(
+            "SOCKS5 proxy couldn't connect: {}",
+            reply_message(reply[1])
+        )));
+    }
+    // The address the proxy bound for us, which we don't care about.
+    TargetAddr::read(io).await?;
+    Ok(())
+}
+
+/// Negotiate authentication with a SOCKS5 client and read its request.
+///
+/// Clients have to authenticate with `credentials` if given. Only CONNECT requests are
+/// accepted, for which the destination is returned.
+async fn accept<T>(io: &mut T, credentials: Option<&(String, String)>) -> io::Result<TargetAddr>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0; 2];
+    io.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(invalid(&format!("unsupported version {}", greeting[0])));
+    }
+    let mut methods = vec![0; greeting[1] as usize];
+    io.read_exact(&mut methods).await?;
+    let method = if credentials.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTH
+    };
+    if !methods.contains(&method) {
+        io.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Client doesn't offer the required authentication method",
+        ));
+    }
+    io.write_all(&[VERSION, method]).await?;
+
+    if let Some((username, password)) = credentials {
+        if io.read_u8().await? != AUTH_VERSION {
+            return Err(invalid("unsupported authentication version"));
+        }
+        let mut given_username = vec![0; io.read_u8().await? as usize];
+        io.read_exact(&mut given_username).await?;
+        let mut given_password = vec![0; io.read_u8().await? as usize];
+        io.read_exact(&mut given_password).await?;
+        // Compare both in constant time so that neither leaks through timing.
+        let valid = verify_slices_are_equal(&given_username, username.as_bytes()).is_ok()
+            & verify_slices_are_equal(&given_password, password.as_bytes()).is_ok();
+        if !valid {
+            io.write_all(&[AUTH_VERSION, GENERAL_FAILURE]).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Invalid credentials for user {}",
+                    String::from_utf8_lossy(&given_username)
+                ),
+            ));
+        }
+        io.write_all(&[AUTH_VERSION, SUCCEEDED]).await?;
+    }
+
+    let mut request = [0; 3];
+    io.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(invalid("unexpected version in request"));
+    }
+    let target = TargetAddr::read(io).await?;
+    if request[1] != CONNECT {
+        reply(io, COMMAND_NOT_SUPPORTED, None).await?;
+        return Err(io::Error::other(format!(
+            "Unsupported command {} for {}",
+            request[1], target
+        )));
+    }
+    Ok(target)
+}
+
+/// Send a reply to a request, including the address we bound for the client if any.
+async fn reply<T>(io: &mut T, reply: u8, bound: Option<SocketAddr>) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let bound = bound.unwrap_or_else(|| (Ipv4Addr::UNSPECIFIED, 0).into());
+    let mut message = vec![VERSION, reply, 0];
+    message.extend_from_slice(&TargetAddr::Ip(bound).to_bytes()?);
+    io.write_all(&message).await
+}
+
+/// Connect to `target` and copy data between it and `client` until both sides are done.
+async fn tunnel<C>(
+    args: &CliArgs,
+    outbound: &Outbound,
+    mut client: C,
+    remote: &str,
+    target: TargetAddr,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let destination = target.to_string();
+    let (host, port) = match target {
+        TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+        TargetAddr::Domain(domain, port) => (domain, port),
+    };
+    let addrs = match check_destination(args, &host, port).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            warn!("Refused SOCKS5 connection from {}: {}", remote, e);
+            return reply(&mut client, NOT_ALLOWED, None).await;
+        }
+    };
+
+    let started = Instant::now();
+    let connect = outbound.connect_addrs(&host, port, &addrs);
+    let upstream = match timeout(Duration::from_secs(args.timeout), connect).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            error!("Couldn't connect to {}: {}", destination, e);
+            let code = match e.kind() {
+                io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+                _ => HOST_UNREACHABLE,
+            };
+            return reply(&mut client, code, None).await;
+        }
+        Err(_) => {
+            error!("Timeout while connecting to {}", destination);
+            return reply(&mut client, HOST_UNREACHABLE, None).await;
+        }
+    };
+    reply(&mut client, SUCCEEDED, upstream.local_addr().ok()).await?;
+    info!(
+        "{}",
+        log_tcp_connection_opened(remote, &destination, None, args.verbose)
+    );
+
+    let (sent, received, error) = splice(client, upstream).await;
+    info!(
+        "{}",
+        log_tcp_connection_closed(
+            remote,
+            &destination,
+            sent,
+            received,
+            started.elapsed(),
+            error.as_ref(),
+            args.verbose,
+        )
+    );
+    Ok(())
+}
+
+/// Serve SOCKS5 on connections of type `S`, see `bind_socks5()`.
+fn socks5_service<S>(
+    args: CliArgs,
+) -> impl ServiceFactory<Config = (), Request = S, Response = (), Error = (), InitError = ()>
+where
+    S: Stream,
+{
+    let outbound = Outbound::new(&args);
+    fn_service(move |mut io: S| {
+        let args = args.clone();
+        let outbound = outbound.clone();
+        async move {
+            let peer_addr = match client_addr(&mut io, args.proxy_protocol).await {
+                Ok(peer_addr) => peer_addr,
+                Err(e) => {
+                    error!("Failed to read PROXY protocol header: {}", e);
+                    return Ok(());
+                }
+            };
+            let remote = peer_addr
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            let accept = accept(&mut io, args.socks5_auth.as_ref());
+            let target = match timeout(PROTOCOL_DETECTION_TIMEOUT, accept).await {
+                Ok(Ok(target)) => target,
+                Ok(Err(e)) => {
+                    warn!("SOCKS5 handshake with {} failed: {}", remote, e);
+                    return Ok(());
+                }
+                Err(_) => {
+                    warn!("Timeout during SOCKS5 handshake with {}", remote);
+                    return Ok(());
+                }
+            };
+            if let Err(e) = tunnel(&args, &outbound, io, &remote, target).await {
+                error!("SOCKS5 connection from {} failed: {}", remote, e);
+            }
+            Ok(())
+        }
+    })
+}
+
+/// Add a listener acting as a SOCKS5 proxy.
+pub fn bind_socks5(
+    builder: ServerBuilder,
+    listener: Listener,
+    args: CliArgs,
+) -> io::Result<ServerBuilder> {
+    let name = format!("proxyboi-socks5-{}", listener);
+    match listener {
+        Listener::Addr(addr) => builder.bind(name, addr, move || {
+            socks5_service::<TcpStream>(args.clone())
+        }),
+        Listener::Tcp(listener) => builder.listen(name, listener, move || {
+            socks5_service::<TcpStream>(args.clone())
+        }),
+        #[cfg(unix)]
+        Listener::Unix(listener) => builder.listen_uds(name, listener, move || {
+            socks5_service::<UnixStream>(args.clone())
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_target_addr_encoding() {
+        assert_eq!(
+            TargetAddr::Ip("192.0.2.1:443".parse().unwrap())
+                .to_bytes()
+                .unwrap(),
+            vec![IPV4, 192, 0, 2, 1, 1, 187]
+        );
+        assert_eq!(
+            TargetAddr::Domain("example.com".to_string(), 80)
+                .to_bytes()
+                .unwrap(),
+            [&[DOMAIN_NAME, 11][..], b"example.com", &[0, 80]].concat()
+        );
+        assert!(TargetAddr::Domain("a".repeat(256), 80).to_bytes().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_target_addr_roundtrip() {
+        for addr in [
+            TargetAddr::Ip("192.0.2.1:443".parse().unwrap()),
+            TargetAddr::Ip("[2001:db8::1]:8080".parse().unwrap()),
+            TargetAddr::Domain("example.com".to_string(), 80),
+        ] {
+            let bytes = addr.to_bytes().unwrap();
+            assert_eq!(TargetAddr::read(&mut &bytes[..]).await.unwrap(), addr);
+        }
+    }
+
+    /// Run our client against our server and return what both sides made of it.
+    #[cfg(unix)]
+    async fn handshake(
+        client_credentials: Option<(&str, &str)>,
+        server_credentials: Option<(String, String)>,
+    ) -> (io::Result<()>, io::Result<TargetAddr>) {
+        let (mut client, mut server) = tokio::net::UnixStream::pair().unwrap();
+        let target = TargetAddr::Domain("example.com".to_string(), 443);
+        futures::future::join(connect(&mut client, &target, client_credentials), async {
+            let target = accept(&mut server, server_credentials.as_ref()).await?;
+            reply(&mut server, SUCCEEDED, None).await?;
+            Ok(target)
+        })
+        .await
+    }
+
+    #[cfg(unix)]
+    #[actix_rt::test]
+    async fn test_handshake() {
+        let (client, server) = handshake(None, None).await;
+        assert!(client.is_ok());
+        assert_eq!(
+            server.unwrap(),
+            TargetAddr::Domain("example.com".to_string(), 443)
+        );
+
+        let credentials = Some(("user".to_string(), "secret".to_string()));
+        let (client, server) = handshake(Some(("user", "secret")), credentials).await;
+        assert!(client.is_ok());
+        assert!(server.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[actix_rt::test]
+    async fn test_handshake_authentication_failure() {
+        let credentials = Some(("user".to_string(), "secret".to_string()));
+        let (client, server) = handshake(Some(("user", "wrong")), credentials.clone()).await;
+        assert_eq!(client.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(server.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let (client, server) = handshake(None, credentials).await;
+        assert_eq!(client.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(server.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+}
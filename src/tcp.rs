@@ -0,0 +1,360 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_rt::net::TcpStream;
+#[cfg(unix)]
+use actix_rt::net::UnixStream;
+use actix_server::ServerBuilder;
+use actix_service::{fn_service, ServiceFactory};
+use anyhow::{anyhow, Result};
+use futures::future::try_join;
+use log::{error, info};
+use rustls::{ServerConfig, Session};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use url::Url;
+
+use crate::args::CliArgs;
+use crate::listener::Listener;
+use crate::logging::{log_tcp_connection_closed, log_tcp_connection_opened};
+use crate::outbound::Outbound;
+use crate::proxy_protocol::{v2_header, ProxiedAddrs};
+use crate::server::{client_addr, Stream, ACME_TLS_ALPN_PROTOCOL, PROTOCOL_DETECTION_TIMEOUT};
+use crate::sni::{peek_server_name, route};
+
+/// Size of the buffer used for copying data in each direction.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Whether `args` ask for raw TCP forwarding instead of proxying HTTP.
+pub fn is_tcp_upstream(args: &CliArgs) -> bool {
+    args.upstream
+        .as_ref()
+        .is_some_and(|upstream| upstream.scheme() == "tcp")
+}
+
+/// Host and port of a `tcp://host:port` upstream.
+pub fn upstream_addr(upstream: &Url) -> io::Result<(String, u16)> {
+    match (upstream.host_str(), upstream.port()) {
+        (Some(host), Some(port)) => Ok((
+            host.trim_matches(|c| c == '[' || c == ']').to_string(),
+            port,
+        )),
+        _ => Err(io::Error::other(format!(
+            "TCP upstream {} needs a host and a port (eg. tcp://localhost:5432)",
+            upstream
+        ))),
+    }
+}
+
+/// Forward connections of type `S` to the upstream, see `bind_tcp()`.
+fn tcp_service<S>(
+    args: CliArgs,
+    tls_config: Option<Arc<ServerConfig>>,
+) -> impl ServiceFactory<Config = (), Request = S, Response = (), Error = (), InitError = ()>
+where
+    S: Stream,
+{
+    let acceptor = tls_config.map(TlsAcceptor::from);
+    let outbound = Outbound::new(&args);
+    fn_service(move |mut io: S| {
+        let args = args.clone();
+        let acceptor = acceptor.clone();
+        let outbound = outbound.clone();
+        async move {
+            let peer_addr = match client_addr(&mut io, args.proxy_protocol).await {
+                Ok(peer_addr) => peer_addr,
+                Err(e) => {
+                    error!("Failed to read PROXY protocol header: {}", e);
+                    return Ok(());
+                }
+            };
+            match acceptor {
+                Some(acceptor) => match timeout(PROTOCOL_DETECTION_TIMEOUT, acceptor.accept(io))
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))
+                {
+                    // ACME TLS-ALPN-01 validation servers only care about our certificate.
+                    Ok(tls_stream)
+                        if tls_stream.get_ref().1.get_alpn_protocol()
+                            == Some(ACME_TLS_ALPN_PROTOCOL) => {}
+                    Ok(tls_stream) => {
+                        let server_name = tls_stream
+                            .get_ref()
+                            .1
+                            .get_sni_hostname()
+                            .map(|name| name.to_string());
+                        tunnel(&args, &outbound, tls_stream, peer_addr, server_name).await
+                    }
+                    Err(e) => error!("TLS handshake with {:?} failed: {}", peer_addr, e),
+                },
+                None if !args.sni_routes.is_empty() => {
+                    match timeout(PROTOCOL_DETECTION_TIMEOUT, peek_server_name(io)).await {
+                        Ok(Ok((server_name, io))) => {
+                            tunnel(&args, &outbound, io, peer_addr, server_name).await
+                        }
+                        Ok(Err(e)) => error!("Failed to read TLS ClientHello: {}", e),
+                        Err(_) => error!("Timeout while waiting for TLS ClientHello"),
+                    }
+                }
+                None => tunnel(&args, &outbound, io, peer_addr, None).await,
+            }
+            Ok(())
+        }
+    })
+}
+
+/// Connect to `upstream` on behalf of the client at `peer_addr`.
+async fn connect_upstream(
+    args: &CliArgs,
+    outbound: &Outbound,
+    upstream: &Url,
+    peer_addr: Option<SocketAddr>,
+) -> Result<TcpStream> {
+    let (host, port) = upstream_addr(upstream)?;
+    let mut stream = timeout(
+        Duration::from_secs(args.timeout),
+        outbound.connect(&host, port),
+    )
+    .await
+    .map_err(|_| anyhow!("Timeout while connecting to {}", upstream))??;
+    if args.upstream_proxy_protocol {
+        let addrs = peer_addr.map(|source| ProxiedAddrs {
+            source,
+            destination: args.listen,
+        });
+        stream.write_all(&v2_header(addrs)).await?;
+    }
+    Ok(stream)
+}
+
+/// Copy data between `client` and a new connection to the upstream until both sides are done.
+///
+/// The upstream is picked according to `--sni-route` if the client asked for `server_name`.
+async fn tunnel<C>(
+    args: &CliArgs,
+    outbound: &Outbound,
+    client: C,
+    peer_addr: Option<SocketAddr>,
+    server_name: Option<String>,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let remote = peer_addr
+        .map(|p| p.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let upstream = server_name
+        .as_deref()
+        .and_then(|server_name| route(&args.sni_routes, server_name))
+        .or(args.upstream.as_ref())
+        .expect("TCP forwarding is only used with a tcp:// upstream");
+    let upstream_uri = upstream.as_str();
+    let started = Instant::now();
+
+    let upstream = match connect_upstream(args, outbound, upstream, peer_addr).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            error!("Couldn't connect to upstream {}: {}", upstream_uri, e);
+            return;
+        }
+    };
+    info!(
+        "{}",
+        log_tcp_connection_opened(&remote, upstream_uri, server_name.as_deref(), args.verbose)
+    );
+
+    let (sent, received, error) = splice(client, upstream).await;
+    info!(
+        "{}",
+        log_tcp_connection_closed(
+            &remote,
+            upstream_uri,
+            sent,
+            received,
+            started.elapsed(),
+            error.as_ref(),
+            args.verbose,
+        )
+    );
+}
+
+/// Copy data between `a` and `b` in both directions until both sides are done.
+///
+/// Returns the number of bytes sent from `a` to `b` and from `b` to `a` along with the error
+/// which ended the connection early, if any.
+pub async fn splice<A, B>(a: A, b: B) -> (u64, u64, Option<io::Error>)
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (a_read, a_write) = tokio::io::split(a);
+    let (b_read, b_write) = tokio::io::split(b);
+    let (mut sent, mut received) = (0, 0);
+    let result = try_join(
+        copy(a_read, b_write, &mut sent),
+        copy(b_read, a_write, &mut received),
+    )
+    .await;
+    (sent, received, result.err())
+}
+
+/// Copy everything from `reader` to `writer`, counting the bytes in `count`.
+///
+/// Once `reader` is done, the write side of `writer` is shut down so that the other end sees
+/// the half-close.
+async fn copy<R, W>(mut reader: R, mut writer: W, count: &mut u64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..read]).await?;
+        *count += read as u64;
+    }
+}
+
+/// Add a listener forwarding raw TCP connections to the `tcp://` upstream.
+///
+/// With `tls_config`, TLS is terminated here and the plain data is forwarded. Otherwise the
+/// connection is passed through untouched, including any TLS spoken on it.
+pub fn bind_tcp(
+    builder: ServerBuilder,
+    listener: Listener,
+    args: CliArgs,
+    tls_config: Option<Arc<ServerConfig>>,
+) -> io::Result<ServerBuilder> {
+    let name = format!("proxyboi-tcp-{}", listener);
+    match listener {
+        Listener::Addr(addr) => builder.bind(name, addr, move || {
+            tcp_service::<TcpStream>(args.clone(), tls_config.clone())
+        }),
+        Listener::Tcp(listener) => builder.listen(name, listener, move || {
+            tcp_service::<TcpStream>(args.clone(), tls_config.clone())
+        }),
+        #[cfg(unix)]
+        Listener::Unix(listener) => builder.listen_uds(name, listener, move || {
+            tcp_service::<UnixStream>(args.clone(), tls_config.clone())
+        }),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use actix_service::Service;
+    use clap::Parser;
+    use futures::future::join;
+    use pretty_assertions::assert_eq;
+    use rustls::{Certificate, ClientConfig, NoClientAuth, PrivateKey};
+    use tokio_rustls::TlsConnector;
+
+    #[actix_rt::test]
+    async fn test_splice() {
+        let (mut client, a) = UnixStream::pair().unwrap();
+        let (b, mut upstream) = UnixStream::pair().unwrap();
+        let (result, ()) = join(splice(a, b), async {
+            client.write_all(b"hello").await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+
+            // The half-close reaches the upstream, which can still answer afterwards.
+            let mut request = vec![];
+            upstream.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"hello");
+            upstream.write_all(b"hello yourself").await.unwrap();
+            upstream.shutdown(std::net::Shutdown::Write).unwrap();
+
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"hello yourself");
+        })
+        .await;
+        let (sent, received, error) = result;
+        assert_eq!((sent, received), (5, 14));
+        assert!(error.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_splice_error() {
+        let (mut client, a) = UnixStream::pair().unwrap();
+        let (b, upstream) = UnixStream::pair().unwrap();
+        drop(upstream);
+        client.write_all(b"hello").await.unwrap();
+        let (sent, received, error) = splice(a, b).await;
+        assert_eq!((sent, received), (0, 0));
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    /// A TLS configuration with a new self-signed certificate for `localhost`.
+    fn tls_configs() -> (Arc<ServerConfig>, TlsConnector) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config
+            .set_single_cert(
+                vec![cert_der.clone()],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut client_config = ClientConfig::new();
+        client_config.root_store.add(&cert_der).unwrap();
+        (
+            Arc::new(server_config),
+            TlsConnector::from(Arc::new(client_config)),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn test_tls_termination() {
+        let mut upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_url = format!("tcp://{}", upstream.local_addr().unwrap());
+        let args = CliArgs::parse_from(["proxyboi", &upstream_url]);
+        let (server_config, connector) = tls_configs();
+        let mut service = tcp_service::<UnixStream>(args, Some(server_config))
+            .new_service(())
+            .await
+            .unwrap();
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let (served, ()) = join(service.call(server), async {
+            let server_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let mut client = connector.connect(server_name, client).await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let (mut upstream, _) = upstream.accept().await.unwrap();
+            let mut request = [0; 5];
+            upstream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"hello");
+            upstream.write_all(b"hello yourself").await.unwrap();
+            drop(upstream);
+
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"hello yourself");
+        })
+        .await;
+        assert!(served.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_tls_handshake_timeout() {
+        let args = CliArgs::parse_from(["proxyboi", "tcp://127.0.0.1:1"]);
+        let (server_config, _) = tls_configs();
+        let mut service = tcp_service::<UnixStream>(args, Some(server_config))
+            .new_service(())
+            .await
+            .unwrap();
+
+        // The client never starts the handshake, the paused clock skips ahead to the timeout.
+        let (_client, server) = UnixStream::pair().unwrap();
+        tokio::time::pause();
+        let started = tokio::time::Instant::now();
+        assert!(service.call(server).await.is_ok());
+        assert!(started.elapsed() >= PROTOCOL_DETECTION_TIMEOUT);
+    }
+}
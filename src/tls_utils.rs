@@ -1,18 +1,22 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::warn;
 use rustls::internal::pemfile::{certs, rsa_private_keys};
+use rustls::{NoClientAuth, NoServerSessionStorage, ProtocolVersion, ServerConfig, Ticketer};
 use std::fs::File;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::parse_x509_certificate;
+
+use crate::args::{CliArgs, TlsVersion};
+use crate::tcp::is_tcp_upstream;
 
 /// Load a certificate from `filename`.
 pub fn load_cert(filename: &Path) -> std::io::Result<Vec<rustls::Certificate>> {
     let certfile = File::open(filename)?;
     let mut reader = BufReader::new(certfile);
-    certs(&mut reader).map_err(|_| {
-        std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "File contains an invalid certificate",
-        )
-    })
+    certs(&mut reader).map_err(|_| std::io::Error::other("File contains an invalid certificate"))
 }
 
 /// Load a private key from `filename`.
@@ -20,20 +24,15 @@ pub fn load_private_key(filename: &Path) -> std::io::Result<rustls::PrivateKey>
     let rsa_keys = {
         let keyfile = File::open(filename)?;
         let mut reader = BufReader::new(keyfile);
-        rsa_private_keys(&mut reader).map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "File contains invalid RSA private key",
-            )
-        })?
+        rsa_private_keys(&mut reader)
+            .map_err(|_| std::io::Error::other("File contains invalid RSA private key"))?
     };
 
     let pkcs8_keys = {
         let keyfile = File::open(filename)?;
         let mut reader = BufReader::new(keyfile);
         rustls::internal::pemfile::pkcs8_private_keys(&mut reader).map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
+            std::io::Error::other(
                 "File contains invalid pkcs8 private key (encrypted keys not supported)",
             )
         })?
@@ -47,3 +46,299 @@ pub fn load_private_key(filename: &Path) -> std::io::Result<rustls::PrivateKey>
         Ok(rsa_keys[0].clone())
     }
 }
+
+/// Get the point in time after which `cert` is no longer valid.
+pub fn certificate_expiry(cert: &rustls::Certificate) -> std::io::Result<DateTime<Utc>> {
+    let (_, parsed) = parse_x509_certificate(&cert.0)
+        .map_err(|_| std::io::Error::other("Certificate could not be parsed as X.509"))?;
+    Utc.timestamp_opt(parsed.validity().not_after.timestamp(), 0)
+        .single()
+        .ok_or_else(|| std::io::Error::other("Certificate has an invalid expiry date"))
+}
+
+/// How often to check whether the served certificate is about to expire.
+const EXPIRY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(12 * 60 * 60);
+
+/// How a certificate's expiry date relates to the present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpiryStatus {
+    Expired,
+    /// Expires within the warning period, in this many (full) days.
+    Expiring(i64),
+    Valid,
+}
+
+impl ExpiryStatus {
+    fn new(expiry: DateTime<Utc>, now: DateTime<Utc>, warning_days: u32) -> Self {
+        let remaining = expiry - now;
+        if remaining < Duration::zero() {
+            ExpiryStatus::Expired
+        } else if remaining < Duration::days(warning_days.into()) {
+            ExpiryStatus::Expiring(remaining.num_days())
+        } else {
+            ExpiryStatus::Valid
+        }
+    }
+}
+
+/// Log a warning if the certificate from `filename` expires within `warning_days`.
+fn warn_if_expiring(filename: &Path, expiry: DateTime<Utc>, warning_days: u32) {
+    match ExpiryStatus::new(expiry, Utc::now(), warning_days) {
+        ExpiryStatus::Expired => warn!(
+            "Certificate {} has expired at {}",
+            filename.display(),
+            expiry
+        ),
+        ExpiryStatus::Expiring(days) => warn!(
+            "Certificate {} expires in {} days (at {})",
+            filename.display(),
+            days,
+            expiry
+        ),
+        ExpiryStatus::Valid => {}
+    }
+}
+
+/// Check the expiry of the certificate loaded from `filename`.
+///
+/// Expired certificates are refused unless `allow_expired` is set. Certificates expiring within
+/// `warning_days` result in a warning.
+pub fn check_expiry(
+    filename: &Path,
+    expiry: DateTime<Utc>,
+    warning_days: u32,
+    allow_expired: bool,
+) -> std::io::Result<()> {
+    if ExpiryStatus::new(expiry, Utc::now(), warning_days) == ExpiryStatus::Expired
+        && !allow_expired
+    {
+        return Err(std::io::Error::other(format!(
+            "Certificate {} has expired at {} (use --allow-expired-cert to start anyway)",
+            filename.display(),
+            expiry
+        )));
+    }
+    warn_if_expiring(filename, expiry, warning_days);
+    Ok(())
+}
+
+/// Periodically warn about the certificate loaded from `filename` expiring soon.
+pub async fn monitor_expiry(filename: PathBuf, expiry: DateTime<Utc>, warning_days: u32) {
+    loop {
+        tokio::time::delay_for(EXPIRY_CHECK_INTERVAL).await;
+        warn_if_expiring(&filename, expiry, warning_days);
+    }
+}
+
+/// Strip the tag and length from a DER encoded value, leaving only its contents.
+fn der_contents(der: &[u8]) -> &[u8] {
+    match der.get(1) {
+        Some(len) if len & 0x80 == 0 => &der[2..],
+        Some(len) => der.get(2 + (len & 0x7f) as usize..).unwrap_or_default(),
+        None => der,
+    }
+}
+
+/// Make sure `certs` is a properly ordered certificate chain.
+///
+/// Every certificate has to be issued (and signed) by the one following it. A chain which ends in
+/// neither a self-signed certificate nor one issued by a well-known root CA is likely missing an
+/// intermediate certificate which only results in a warning as it's perfectly valid when using a
+/// private CA.
+pub fn validate_chain(certs: &[rustls::Certificate]) -> std::io::Result<()> {
+    let parsed = certs
+        .iter()
+        .map(|cert| {
+            parse_x509_certificate(&cert.0)
+                .map(|(_, parsed)| parsed)
+                .map_err(|_| std::io::Error::other("Certificate could not be parsed as X.509"))
+        })
+        .collect::<std::io::Result<Vec<X509Certificate>>>()?;
+
+    let leaf = parsed
+        .first()
+        .ok_or_else(|| std::io::Error::other("File contains no certificates"))?;
+
+    for (position, pair) in parsed.windows(2).enumerate() {
+        let (child, parent) = (&pair[0], &pair[1]);
+        if child.issuer() != parent.subject()
+            || child.verify_signature(Some(parent.public_key())).is_err()
+        {
+            return Err(std::io::Error::other(format!(
+                "Certificate chain is out of order: certificate {} ({}) is not issued by certificate {} ({})",
+                position,
+                child.subject(),
+                position + 1,
+                parent.subject()
+            )));
+        }
+    }
+
+    let last = parsed.last().unwrap_or(leaf);
+    let self_signed = last.issuer() == last.subject();
+    let issued_by_known_root = webpki_roots::TLS_SERVER_ROOTS
+        .0
+        .iter()
+        .any(|anchor| anchor.subject == der_contents(last.issuer().as_raw()));
+    if !self_signed && !issued_by_known_root {
+        warn!(
+            "Certificate chain for {} ends with a certificate issued by {} which is not a known root CA, the chain might be incomplete",
+            leaf.subject(),
+            last.issuer()
+        );
+    }
+
+    Ok(())
+}
+
+/// Build a rustls `ServerConfig` according to the TLS options in `args`.
+///
+/// Setting up the certificates is left to the caller.
+pub fn server_config(args: &CliArgs) -> std::io::Result<ServerConfig> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.versions = match args.tls_min_version {
+        TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    };
+
+    if !args.tls_ciphers.is_empty() {
+        config.ciphersuites = args.tls_ciphers.clone();
+    }
+    let usable = config.ciphersuites.iter().any(|suite| {
+        config
+            .versions
+            .iter()
+            .any(|version| suite.usable_for_version(*version))
+    });
+    if !usable {
+        return Err(std::io::Error::other(
+            "None of the given cipher suites can be used with the allowed TLS versions",
+        ));
+    }
+
+    // The protocol spoken over forwarded TCP connections isn't ours to negotiate.
+    if !is_tcp_upstream(args) {
+        let protocols = args
+            .tls_alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        config.set_protocols(&protocols);
+    }
+
+    if args.tls_session_tickets {
+        config.ticketer = Ticketer::new();
+    }
+    if args.no_tls_session_resumption {
+        config.session_storage = Arc::new(NoServerSessionStorage {});
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use rcgen::{BasicConstraints, Certificate as GeneratedCertificate, CertificateParams, IsCa};
+
+    fn ca() -> GeneratedCertificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        GeneratedCertificate::from_params(params).unwrap()
+    }
+
+    fn leaf() -> GeneratedCertificate {
+        GeneratedCertificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+            .unwrap()
+    }
+
+    fn alpn_protocols(alpn: &str) -> Result<Vec<Vec<u8>>, clap::Error> {
+        let args =
+            CliArgs::try_parse_from(["proxyboi", "--tls-alpn", alpn, "http://localhost:3000"])?;
+        Ok(server_config(&args).unwrap().alpn_protocols)
+    }
+
+    #[test]
+    fn test_alpn_protocols() {
+        assert_eq!(
+            alpn_protocols("http/1.1, h2").unwrap(),
+            [b"http/1.1".to_vec(), b"h2".to_vec()]
+        );
+        assert_eq!(alpn_protocols("http/1.1").unwrap(), [b"http/1.1".to_vec()]);
+        assert!(alpn_protocols("h3").is_err());
+        assert!(alpn_protocols("h2,spdy/3").is_err());
+        assert!(alpn_protocols("").is_err());
+    }
+
+    #[test]
+    fn test_self_signed_chain() {
+        let cert = rustls::Certificate(leaf().serialize_der().unwrap());
+        assert!(validate_chain(&[cert]).is_ok());
+    }
+
+    #[test]
+    fn test_ordered_chain() {
+        let ca = ca();
+        let chain = [
+            rustls::Certificate(leaf().serialize_der_with_signer(&ca).unwrap()),
+            rustls::Certificate(ca.serialize_der().unwrap()),
+        ];
+        assert!(validate_chain(&chain).is_ok());
+    }
+
+    #[test]
+    fn test_misordered_chain() {
+        let ca = ca();
+        let chain = [
+            rustls::Certificate(ca.serialize_der().unwrap()),
+            rustls::Certificate(leaf().serialize_der_with_signer(&ca).unwrap()),
+        ];
+        assert!(validate_chain(&chain).is_err());
+    }
+
+    #[test]
+    fn test_expiry_status() {
+        let now = Utc::now();
+        assert_eq!(
+            ExpiryStatus::new(now - Duration::days(1), now, 30),
+            ExpiryStatus::Expired
+        );
+        assert_eq!(
+            ExpiryStatus::new(now + Duration::days(10) + Duration::hours(1), now, 30),
+            ExpiryStatus::Expiring(10)
+        );
+        assert_eq!(
+            ExpiryStatus::new(now + Duration::hours(1), now, 30),
+            ExpiryStatus::Expiring(0)
+        );
+        assert_eq!(
+            ExpiryStatus::new(now + Duration::days(31), now, 30),
+            ExpiryStatus::Valid
+        );
+        assert_eq!(
+            ExpiryStatus::new(now + Duration::days(1), now, 0),
+            ExpiryStatus::Valid
+        );
+    }
+
+    #[test]
+    fn test_check_expiry() {
+        let filename = Path::new("cert.pem");
+        let now = Utc::now();
+        assert!(check_expiry(filename, now - Duration::days(1), 30, false).is_err());
+        assert!(check_expiry(filename, now - Duration::days(1), 30, true).is_ok());
+        assert!(check_expiry(filename, now + Duration::days(10), 30, false).is_ok());
+        assert!(check_expiry(filename, now + Duration::days(60), 30, false).is_ok());
+    }
+
+    #[test]
+    fn test_chain_with_foreign_certificate() {
+        let chain = [
+            rustls::Certificate(leaf().serialize_der_with_signer(&ca()).unwrap()),
+            rustls::Certificate(ca().serialize_der().unwrap()),
+        ];
+        assert!(validate_chain(&chain).is_err());
+    }
+}
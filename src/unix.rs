@@ -0,0 +1,183 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::task::{Context, Poll};
+
+use actix_connect::{Connect, ConnectError, Connection};
+use actix_service::Service;
+use actix_web::http::Uri;
+use futures::future::LocalBoxFuture;
+use url::Url;
+
+#[cfg(unix)]
+pub use tokio::net::UnixStream;
+
+/// Stand-in for platforms without Unix domain sockets, see `connect()`.
+#[cfg(not(unix))]
+pub type UnixStream = tokio::net::TcpStream;
+
+/// Connect to the Unix domain socket at `path`.
+#[cfg(unix)]
+pub async fn connect(path: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(path).await
+}
+
+/// Connect to the Unix domain socket at `path`.
+///
+/// Always fails as Unix domain sockets are not supported on this platform.
+#[cfg(not(unix))]
+pub async fn connect(_path: &Path) -> io::Result<UnixStream> {
+    Err(io::Error::other(
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// An upstream reachable through a Unix domain socket.
+///
+/// Given as `unix:///run/app.sock`, optionally followed by a path prefix to put in front of all
+/// request paths like `unix:///run/app.sock:/api`.
+#[derive(Debug, Clone)]
+pub struct UnixUpstream {
+    pub socket: PathBuf,
+    pub prefix: String,
+}
+
+impl UnixUpstream {
+    /// Parse `upstream` if it points at a Unix domain socket.
+    ///
+    /// The prefix starts at the first `:/`, so the socket path may contain colons otherwise.
+    pub fn parse(upstream: &Url) -> Result<Option<Self>, String> {
+        if upstream.scheme() != "unix" {
+            return Ok(None);
+        }
+        let (socket, prefix) = match upstream.path().find(":/") {
+            Some(colon) => (&upstream.path()[..colon], &upstream.path()[colon + 1..]),
+            None => (upstream.path(), ""),
+        };
+        let has_host = upstream.host_str().is_some_and(|host| !host.is_empty());
+        if has_host || socket.trim_matches('/').is_empty() {
+            return Err(format!(
+                "Missing socket path in {} (expected unix:///path/to/socket[:/prefix])",
+                upstream
+            ));
+        }
+        Ok(Some(UnixUpstream {
+            socket: PathBuf::from(socket),
+            prefix: prefix.trim_end_matches('/').to_string(),
+        }))
+    }
+
+    /// Parse `upstream` if it points at a Unix domain socket, which `parse()` checked on startup.
+    pub fn from_url(upstream: &Url) -> Option<Self> {
+        Self::parse(upstream).ok().flatten()
+    }
+
+    /// The URL to request over the socket for `path` and `query`.
+    ///
+    /// The host is meaningless as we always connect to the socket, so it's just `localhost`.
+    pub fn url(&self, path: &str, query: Option<&str>) -> Url {
+        let mut url = Url::parse("http://localhost").expect("Static URL is valid");
+        url.set_path(&format!("{}{}", self.prefix, path));
+        url.set_query(query);
+        url
+    }
+}
+
+/// awc connector connecting to a Unix domain socket regardless of the requested host.
+#[derive(Debug, Clone)]
+pub struct UnixConnector {
+    socket: PathBuf,
+}
+
+impl UnixConnector {
+    pub fn new(socket: PathBuf) -> Self {
+        UnixConnector { socket }
+    }
+}
+
+impl Service for UnixConnector {
+    type Request = Connect<Uri>;
+    type Response = Connection<Uri, UnixStream>;
+    type Error = ConnectError;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: Connect<Uri>) -> Self::Future {
+        let socket = self.socket.clone();
+        Box::pin(async move {
+            let stream = connect(&socket).await?;
+            Ok(Connection::new(stream, Uri::default()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn parse(upstream: &str) -> Result<Option<(String, String)>, String> {
+        let upstream = UnixUpstream::parse(&Url::parse(upstream).unwrap())?;
+        Ok(upstream.map(|upstream| (upstream.socket.display().to_string(), upstream.prefix)))
+    }
+
+    fn unix(socket: &str, prefix: &str) -> Result<Option<(String, String)>, String> {
+        Ok(Some((socket.to_string(), prefix.to_string())))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("unix:///run/app.sock"), unix("/run/app.sock", ""));
+        assert_eq!(
+            parse("unix:///run/app.sock:/api"),
+            unix("/run/app.sock", "/api")
+        );
+        assert_eq!(
+            parse("unix:///run/app.sock:/api/"),
+            unix("/run/app.sock", "/api")
+        );
+        assert_eq!(parse("unix:///run/app.sock:/"), unix("/run/app.sock", ""));
+        assert_eq!(parse("unix:/run/app.sock"), unix("/run/app.sock", ""));
+        assert_eq!(parse("http://localhost:3000/app"), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_colon_in_socket_path() {
+        assert_eq!(
+            parse("unix:///run/app:v2.sock"),
+            unix("/run/app:v2.sock", "")
+        );
+        assert_eq!(
+            parse("unix:///run/app:v2.sock:/api:v2"),
+            unix("/run/app:v2.sock", "/api:v2")
+        );
+    }
+
+    #[test]
+    fn test_parse_missing_socket() {
+        assert!(parse("unix://").is_err());
+        assert!(parse("unix:///").is_err());
+        assert!(parse("unix:///:/api").is_err());
+        assert!(parse("unix://run/app.sock").is_err());
+    }
+
+    #[test]
+    fn test_url() {
+        let upstream =
+            UnixUpstream::from_url(&Url::parse("unix:///run/app.sock:/api/").unwrap()).unwrap();
+        assert_eq!(
+            upstream.url("/users", Some("page=2")).as_str(),
+            "http://localhost/api/users?page=2"
+        );
+        assert_eq!(upstream.url("/", None).as_str(), "http://localhost/api/");
+
+        let upstream =
+            UnixUpstream::from_url(&Url::parse("unix:///run/app.sock").unwrap()).unwrap();
+        assert_eq!(
+            upstream.url("/users", None).as_str(),
+            "http://localhost/users"
+        );
+    }
+}
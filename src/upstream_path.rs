@@ -0,0 +1,143 @@
+use url::Url;
+
+use crate::args::CliArgs;
+use crate::unix::UnixUpstream;
+
+/// Remove `prefix` from `path` if it matches whole path segments, leaving at least `/`.
+fn strip_segments<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    path.strip_prefix(prefix)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .map(|rest| if rest.is_empty() { "/" } else { rest })
+}
+
+/// Apply `--strip-path-prefix` and `--add-path-prefix` to the path of an incoming request.
+///
+/// Prefixes only match whole path segments, so `/api` is stripped from `/api/users` but not from
+/// `/apiary`. Paths not starting with the prefix to strip are left alone.
+fn map_path(path: &str, strip_prefix: Option<&str>, add_prefix: Option<&str>) -> String {
+    let stripped = strip_prefix
+        .and_then(|prefix| strip_segments(path, prefix))
+        .unwrap_or(path);
+    match add_prefix {
+        Some(prefix) => format!("{}{}", prefix, stripped),
+        None => stripped.to_string(),
+    }
+}
+
+/// Join the path of the upstream URL with the path of a request.
+///
+/// `/app/` and `/app` both put requests for `/users` at `/app/users`.
+fn join_path(base: &str, path: &str) -> String {
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+/// The URL to send a request for `path` and `query` to at `upstream`.
+pub fn upstream_url(args: &CliArgs, upstream: &Url, path: &str, query: Option<&str>) -> Url {
+    let path = map_path(
+        path,
+        args.strip_path_prefix.as_deref(),
+        args.add_path_prefix.as_deref(),
+    );
+    match UnixUpstream::from_url(upstream) {
+        Some(unix) => unix.url(&path, query),
+        None => {
+            let mut url = upstream.clone();
+            url.set_path(&join_path(upstream.path(), &path));
+            url.set_query(query);
+            url
+        }
+    }
+}
+
+/// Undo `map_path()`, giving the path a client would request to end up at `path`.
+///
+/// Paths which no request can be mapped to give `None`.
+fn unmap_path(path: &str, strip_prefix: Option<&str>, add_prefix: Option<&str>) -> Option<String> {
+    let path = match add_prefix {
+        Some(prefix) => strip_segments(path, prefix)?,
+        None => path,
+    };
+    Some(match strip_prefix {
+        Some(prefix) => format!("{}{}", prefix, path),
+        None => path.to_string(),
+    })
+}
+
+/// The path a client would request to end up at `path` on `upstream`, the reverse of
+/// `upstream_url()`.
+///
+/// Paths outside of the part of the upstream we expose give `None`.
+pub fn public_path(args: &CliArgs, upstream: &Url, path: &str) -> Option<String> {
+    let base = match UnixUpstream::from_url(upstream) {
+        Some(unix) => unix.prefix,
+        None => upstream.path().trim_end_matches('/').to_string(),
+    };
+    unmap_path(
+        strip_segments(path, &base)?,
+        args.strip_path_prefix.as_deref(),
+        args.add_path_prefix.as_deref(),
+    )
+}
+
+/// Bring a path prefix given on the command line into the form `/prefix`.
+pub fn parse_prefix(prefix: &str) -> Result<String, String> {
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() {
+        return Err("Path prefix must not be empty".to_string());
+    }
+    Ok(format!("/{}", prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!(join_path("/", "/users"), "/users");
+        assert_eq!(join_path("/app/", "/users"), "/app/users");
+        assert_eq!(join_path("/app", "/users"), "/app/users");
+        assert_eq!(join_path("/app/", "/"), "/app/");
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        assert_eq!(map_path("/api/users", Some("/api"), None), "/users");
+        assert_eq!(map_path("/api", Some("/api"), None), "/");
+        assert_eq!(map_path("/api/", Some("/api"), None), "/");
+        assert_eq!(map_path("/apiary", Some("/api"), None), "/apiary");
+        assert_eq!(map_path("/other", Some("/api"), None), "/other");
+    }
+
+    #[test]
+    fn test_add_prefix() {
+        assert_eq!(map_path("/users", None, Some("/v1")), "/v1/users");
+        assert_eq!(
+            map_path("/api/users", Some("/api"), Some("/v1")),
+            "/v1/users"
+        );
+    }
+
+    #[test]
+    fn test_unmap_path() {
+        assert_eq!(unmap_path("/users", None, None).unwrap(), "/users");
+        assert_eq!(
+            unmap_path("/users", Some("/api"), None).unwrap(),
+            "/api/users"
+        );
+        assert_eq!(unmap_path("/", Some("/api"), None).unwrap(), "/api/");
+        assert_eq!(
+            unmap_path("/v1/users", Some("/api"), Some("/v1")).unwrap(),
+            "/api/users"
+        );
+        assert_eq!(unmap_path("/v2/users", None, Some("/v1")), None);
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(parse_prefix("api").unwrap(), "/api");
+        assert_eq!(parse_prefix("/api/v1/").unwrap(), "/api/v1");
+        assert!(parse_prefix("/").is_err());
+    }
+}